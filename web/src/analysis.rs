@@ -0,0 +1,548 @@
+use crate::averaging::Averaging;
+use crate::bootstrap::BootstrapOptions;
+use crate::decoupling::{
+    combine_effort_with_time, speed_from_distance, Decoupling, DecouplingMode, DecouplingOptions,
+    DecouplingResult, EffortAtTime,
+};
+use crate::detection::detect_window;
+use crate::dfa::{DfaAlpha1, DfaOptions};
+use crate::filters::{FilterKind, FilterPipeline, FilterReport, Filtered};
+use crate::grade::{ascent_rates, grade_adjusted_speeds, grades, GradeOptions};
+use crate::heart_rate_drift::{
+    combine_hr_with_time, BeatsPerMinute, DriftSettings, HeartRateAtTime, HeartRateDrift,
+    HeartRateDriftError, Seconds,
+};
+use crate::lag::{shift_heart_rate, CardiacLag, LagOptions};
+use crate::laps::{laps_in_moving_time, laps_in_stream_order, protocol_from_laps, Lap, LapOptions};
+use crate::moving::{moving_from_speed, moving_time, MovingOptions, MovingTime, Stops};
+use crate::protocol::ProtocolOptions;
+use crate::quality::QualityThresholds;
+use crate::report::DriftReport;
+use crate::rr::{clean_beats, Beat, RrOptions};
+use crate::temperature::TemperatureOptions;
+use crate::trend::Fit;
+use crate::validation::{validate_streams, Repair, Repairs, StreamOrder};
+use serde::Deserialize;
+
+/// Everything about how an activity is measured, from the command line or the wasm options
+#[derive(clap::Args, Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AnalysisOptions {
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub protocol: ProtocolOptions,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub lap_options: LapOptions,
+
+    /// Fix problems with the streams, like samples out of time order, instead of refusing to
+    /// calculate. Can be given more than once
+    #[arg(long = "repair", value_enum)]
+    pub repairs: Vec<Repair>,
+
+    /// How samples are averaged within a segment. Trapezoidal or step-hold stop smart recording
+    /// from skewing the averages
+    #[arg(long, value_enum, default_value_t = AnalysisOptions::default().averaging)]
+    pub averaging: Averaging,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub quality: QualityThresholds,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub moving: MovingOptions,
+
+    /// Remove artifacts from the heart rate before calculating. Can be given more than once,
+    /// and the filters run in the order given
+    #[arg(long = "filter", value_enum)]
+    pub filters: Vec<FilterKind>,
+
+    /// How the line is fitted for the trend, which is reported alongside the drift
+    #[arg(long, value_enum, default_value_t = AnalysisOptions::default().fit)]
+    pub fit: Fit,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub bootstrap: BootstrapOptions,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub decoupling: DecouplingOptions,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub grade: GradeOptions,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub lag: LagOptions,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub temperature: TemperatureOptions,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub rr: RrOptions,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub dfa: DfaOptions,
+}
+
+/// The streams of an activity. Apart from the laps and beats, every stream that's there has a
+/// value for each time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Streams {
+    pub heart_rates: Vec<BeatsPerMinute>,
+    pub times: Vec<Seconds>,
+    /// Metres per second. Worked out from the distances when missing
+    pub speeds: Option<Vec<f64>>,
+    /// Cumulative metres
+    pub distances: Option<Vec<f64>>,
+    pub watts: Option<Vec<f64>>,
+    pub cadence: Option<Vec<f64>>,
+    pub moving: Option<Vec<bool>>,
+    /// Metres above sea level
+    pub altitudes: Option<Vec<f64>>,
+    /// °C
+    pub temperatures: Option<Vec<f64>>,
+    pub laps: Option<Vec<Lap>>,
+    /// Beat-to-beat data, which has its own times
+    pub beats: Option<Vec<Beat>>,
+}
+
+/// Picks samples out of every stream the same way
+trait Selection {
+    fn select<T: Copy>(&self, values: &[T]) -> Vec<T>;
+}
+
+impl Selection for StreamOrder {
+    fn select<T: Copy>(&self, values: &[T]) -> Vec<T> {
+        self.apply(values)
+    }
+}
+
+impl Selection for MovingTime {
+    fn select<T: Copy>(&self, values: &[T]) -> Vec<T> {
+        self.retain(values)
+    }
+}
+
+impl Selection for Filtered {
+    fn select<T: Copy>(&self, values: &[T]) -> Vec<T> {
+        self.retain(values)
+    }
+}
+
+/// The activity ready to measure: validated, with the stops left out when asked, and filtered
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prepared {
+    pub streams: Streams,
+    pub samples: Vec<HeartRateAtTime>,
+    /// With the segments laid over the laps, when there are laps
+    pub settings: DriftSettings,
+    pub repairs: Repairs,
+    pub stops: Option<Stops>,
+    pub filters: FilterReport,
+}
+
+impl Streams {
+    /// Each stream's length, named by its Strava stream key like everywhere else the streams
+    /// are checked
+    fn lengths(&self) -> Vec<(&'static str, usize)> {
+        let optional = [
+            ("velocity_smooth", self.speeds.as_ref().map(Vec::len)),
+            ("distance", self.distances.as_ref().map(Vec::len)),
+            ("watts", self.watts.as_ref().map(Vec::len)),
+            ("cadence", self.cadence.as_ref().map(Vec::len)),
+            ("moving", self.moving.as_ref().map(Vec::len)),
+            ("altitude", self.altitudes.as_ref().map(Vec::len)),
+            ("temp", self.temperatures.as_ref().map(Vec::len)),
+        ];
+        std::iter::once(("heartrate", self.heart_rates.len()))
+            .chain(
+                optional
+                    .into_iter()
+                    .filter_map(|(stream, length)| length.map(|length| (stream, length))),
+            )
+            .collect()
+    }
+
+    /// The samples `selection` keeps from every stream that has a value for each time
+    fn select(&self, selection: &impl Selection) -> Self {
+        let select =
+            |values: &Option<Vec<f64>>| values.as_ref().map(|values| selection.select(values));
+        Self {
+            heart_rates: selection.select(&self.heart_rates),
+            times: selection.select(&self.times),
+            speeds: select(&self.speeds),
+            distances: select(&self.distances),
+            watts: select(&self.watts),
+            cadence: select(&self.cadence),
+            moving: self.moving.as_ref().map(|moving| selection.select(moving)),
+            altitudes: select(&self.altitudes),
+            temperatures: select(&self.temperatures),
+            laps: self.laps.clone(),
+            beats: self.beats.clone(),
+        }
+    }
+
+    /// Checks the streams line up and are in time order, leaves out the stops when measuring
+    /// on moving time, lays the segments over the laps and runs the filters
+    pub fn prepare(&self, options: &AnalysisOptions) -> Result<Prepared, HeartRateDriftError> {
+        if self.laps.is_some() {
+            let protocol = &options.protocol;
+            let ignored = [
+                ("start", protocol.start.is_some()),
+                ("warm-up", protocol.warm_up.is_some()),
+                ("segment length", protocol.segment_length.is_some()),
+                ("cool-down", protocol.cool_down.is_some()),
+            ];
+            if let Some(&(option, _)) = ignored.iter().find(|(_, given)| *given) {
+                return Err(HeartRateDriftError::OptionIgnoredByLaps { option });
+            }
+        }
+        let order = validate_streams(&self.times, &self.lengths(), &options.repairs)?;
+        let mut streams = self.select(&order);
+        streams.laps = streams.laps.map(|laps| laps_in_stream_order(&laps, &order));
+        if streams.speeds.is_none() {
+            streams.speeds = streams
+                .distances
+                .as_ref()
+                .map(|distances| speed_from_distance(distances, &streams.times));
+        }
+
+        let mut stops = None;
+        if options.moving.moving_time {
+            let moving = match (&streams.moving, &streams.speeds) {
+                (Some(moving), _) => moving.clone(),
+                (None, Some(speeds)) => moving_from_speed(speeds, options.moving.stop_speed),
+                (None, None) => {
+                    return Err(HeartRateDriftError::MissingStream {
+                        stream: "moving or speed",
+                        needed_by: "Moving time",
+                    })
+                }
+            };
+            let moving_time = moving_time(&streams.times, &moving)?;
+            let mut moving_streams = streams.select(&moving_time);
+            moving_streams.laps = streams
+                .laps
+                .map(|laps| laps_in_moving_time(&laps, &moving_time));
+            moving_streams.moving = None;
+            moving_streams.times = moving_time.times;
+            streams = moving_streams;
+            stops = Some(moving_time.stops);
+        }
+
+        let mut settings = DriftSettings {
+            protocol: options.protocol.protocol(),
+            averaging: options.averaging,
+            quality: options.quality,
+            fit: options.fit,
+            bootstrap: options.bootstrap,
+        };
+        if let Some(laps) = &streams.laps {
+            settings.protocol = protocol_from_laps(
+                laps,
+                &streams.times,
+                settings.protocol.segments,
+                &options.lap_options,
+            )?;
+        }
+
+        let samples = combine_hr_with_time(&streams.heart_rates, &streams.times);
+        let filtered = FilterPipeline::from_kinds(&options.filters)
+            .run(&samples, streams.cadence.as_deref())?;
+        Ok(Prepared {
+            streams: streams.select(&filtered),
+            samples: filtered.retain(&samples),
+            settings,
+            repairs: order.repairs,
+            stops,
+            filters: filtered.report,
+        })
+    }
+
+    /// The stream the heart rate is compared against in the given mode
+    fn outputs(
+        &self,
+        options: &DecouplingOptions,
+    ) -> Result<Option<Vec<f64>>, HeartRateDriftError> {
+        let missing = |stream, needed_by| HeartRateDriftError::MissingStream { stream, needed_by };
+        match options.mode {
+            DecouplingMode::HeartRate => Ok(None),
+            DecouplingMode::Pace => self
+                .speeds
+                .clone()
+                .map(Some)
+                .ok_or_else(|| missing("speed or distance", "Pace mode")),
+            DecouplingMode::Power => self
+                .watts
+                .clone()
+                .map(Some)
+                .ok_or_else(|| missing("watts", "Power mode")),
+            DecouplingMode::Vertical => {
+                let altitudes = self
+                    .altitudes
+                    .as_deref()
+                    .ok_or_else(|| missing("altitude", "Vertical mode"))?;
+                Ok(Some(ascent_rates(
+                    altitudes,
+                    &self.times,
+                    options.ascent_smoothing,
+                )?))
+            }
+        }
+    }
+
+    /// Speed adjusted for the grade it was run at
+    fn grade_adjusted_speeds(&self, smoothing: f64) -> Result<Vec<f64>, HeartRateDriftError> {
+        let missing = |stream| HeartRateDriftError::MissingStream {
+            stream,
+            needed_by: "Grade-adjusted pace",
+        };
+        let speeds = self.speeds.as_deref().ok_or_else(|| missing("speed"))?;
+        let altitudes = self
+            .altitudes
+            .as_deref()
+            .ok_or_else(|| missing("altitude"))?;
+        let distances = self
+            .distances
+            .as_deref()
+            .ok_or_else(|| missing("distance"))?;
+        Ok(grade_adjusted_speeds(
+            speeds,
+            &grades(altitudes, distances, smoothing)?,
+        ))
+    }
+}
+
+impl Prepared {
+    /// The drift, along with whatever else the streams and options allow: decoupling against
+    /// the mode's output, temperature, cardiac lag and, from the beats, DFA α1 and HRV
+    pub fn report(&self, options: &AnalysisOptions) -> Result<DriftReport, HeartRateDriftError> {
+        let streams = &self.streams;
+        let outputs = streams.outputs(&options.decoupling)?;
+        let grade_adjusted = if options.grade.grade_adjusted {
+            if options.decoupling.mode != DecouplingMode::Pace {
+                return Err(HeartRateDriftError::GradeAdjustedNeedsPace);
+            }
+            Some(streams.grade_adjusted_speeds(options.grade.grade_smoothing)?)
+        } else {
+            None
+        };
+
+        let mut settings = self.settings.clone();
+        let detected_window = if options.protocol.detect_window {
+            let steadiness = outputs.as_deref().or(streams.speeds.as_deref());
+            let detected = detect_window(&self.samples, steadiness, &settings.protocol)
+                .ok_or(HeartRateDriftError::TestNotFound)?;
+            settings.protocol = detected.protocol.clone();
+            Some(detected)
+        } else {
+            None
+        };
+
+        let mut report = self.samples.heart_rate_drift(&settings)?;
+        report.detected_window = detected_window;
+        report.stops = self.stops.clone();
+        let temperatures: Vec<(Seconds, f64)> = match &streams.temperatures {
+            Some(temperatures) => streams
+                .times
+                .iter()
+                .copied()
+                .zip(temperatures.iter().copied())
+                .collect(),
+            None => vec![],
+        };
+        if !temperatures.is_empty() || options.temperature.ambient_temperature.is_some() {
+            report =
+                report.with_temperature(&temperatures, options.averaging, &options.temperature);
+        }
+
+        if let Some(outputs) = outputs {
+            let efforts = combine_effort_with_time(&streams.heart_rates, &outputs, &streams.times);
+            report.cardiac_lag = options.lag.cardiac_lag(&efforts);
+            let decoupling = lagged_decoupling(efforts, report.cardiac_lag, &settings, options)?;
+            report = report.with_decoupling(decoupling);
+        }
+        if let Some(grade_adjusted) = grade_adjusted {
+            let efforts =
+                combine_effort_with_time(&streams.heart_rates, &grade_adjusted, &streams.times);
+            let decoupling = lagged_decoupling(efforts, report.cardiac_lag, &settings, options)?;
+            report = report.with_grade_adjusted_decoupling(decoupling);
+        }
+        if let Some(beats) = &streams.beats {
+            let clean = clean_beats(beats, &options.rr);
+            report.dfa_alpha1 = Some(DfaAlpha1::new(&clean, &options.dfa));
+            report = report.with_hrv(&clean);
+        }
+        Ok(report)
+    }
+}
+
+/// Decoupling of `efforts`, with the heart rate moved back by the cardiac lag when there is one
+fn lagged_decoupling(
+    efforts: Vec<EffortAtTime>,
+    lag: Option<CardiacLag>,
+    settings: &DriftSettings,
+    options: &AnalysisOptions,
+) -> Result<DecouplingResult, HeartRateDriftError> {
+    let efforts = match lag {
+        Some(lag) => shift_heart_rate(&efforts, lag.seconds),
+        None => efforts,
+    };
+    efforts.decoupling(settings, options.decoupling.output_average())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steady_run() -> Streams {
+        let times: Vec<Seconds> = (0..4500).map(f64::from).collect();
+        Streams {
+            heart_rates: times
+                .iter()
+                .map(|&time| 140 + (time / 300.0) as i32)
+                .collect(),
+            speeds: Some(vec![3.0; times.len()]),
+            times,
+            ..Streams::default()
+        }
+    }
+
+    #[test]
+    fn test_pace_mode_reports_decoupling() {
+        let options = AnalysisOptions {
+            decoupling: DecouplingOptions {
+                mode: DecouplingMode::Pace,
+                ..DecouplingOptions::default()
+            },
+            ..AnalysisOptions::default()
+        };
+
+        let report = steady_run()
+            .prepare(&options)
+            .unwrap()
+            .report(&options)
+            .unwrap();
+
+        assert!(report.decoupling.is_some());
+    }
+
+    #[test]
+    fn test_streams_are_named_by_their_strava_keys() {
+        let mut streams = steady_run();
+        streams.heart_rates.pop();
+
+        assert_eq!(
+            Err(HeartRateDriftError::LengthMismatch {
+                stream: "heartrate",
+                expected: 4500,
+                found: 4499
+            }),
+            streams.prepare(&AnalysisOptions::default())
+        );
+    }
+
+    #[test]
+    fn test_laps_refuse_options_they_would_override() {
+        let streams = Streams {
+            laps: Some(vec![
+                Lap {
+                    start_index: 0,
+                    end_index: 900,
+                },
+                Lap {
+                    start_index: 900,
+                    end_index: 2700,
+                },
+                Lap {
+                    start_index: 2700,
+                    end_index: 4499,
+                },
+            ]),
+            ..steady_run()
+        };
+        let options = AnalysisOptions {
+            protocol: ProtocolOptions {
+                cool_down: Some(5),
+                ..ProtocolOptions::default()
+            },
+            ..AnalysisOptions::default()
+        };
+
+        assert!(streams.prepare(&AnalysisOptions::default()).is_ok());
+        assert_eq!(
+            Err(HeartRateDriftError::OptionIgnoredByLaps {
+                option: "cool-down"
+            }),
+            streams.prepare(&options)
+        );
+    }
+
+    #[test]
+    fn test_power_mode_needs_watts() {
+        let options = AnalysisOptions {
+            decoupling: DecouplingOptions {
+                mode: DecouplingMode::Power,
+                ..DecouplingOptions::default()
+            },
+            ..AnalysisOptions::default()
+        };
+
+        let report = steady_run().prepare(&options).unwrap().report(&options);
+
+        assert_eq!(
+            Err(HeartRateDriftError::MissingStream {
+                stream: "watts",
+                needed_by: "Power mode"
+            }),
+            report
+        );
+    }
+
+    #[test]
+    fn test_grade_adjusted_pace_needs_pace_mode() {
+        let options = AnalysisOptions {
+            grade: GradeOptions {
+                grade_adjusted: true,
+                ..GradeOptions::default()
+            },
+            ..AnalysisOptions::default()
+        };
+
+        let report = steady_run().prepare(&options).unwrap().report(&options);
+
+        assert_eq!(Err(HeartRateDriftError::GradeAdjustedNeedsPace), report);
+    }
+
+    #[test]
+    fn test_moving_time_takes_the_stops_out_of_every_stream() {
+        let mut streams = steady_run();
+        let mut speeds = vec![3.0; streams.times.len()];
+        speeds[1000..1060].fill(0.0);
+        streams.speeds = Some(speeds);
+        let options = AnalysisOptions {
+            moving: MovingOptions {
+                moving_time: true,
+                ..MovingOptions::default()
+            },
+            ..AnalysisOptions::default()
+        };
+
+        let prepared = streams.prepare(&options).unwrap();
+
+        assert_eq!(4440, prepared.samples.len());
+        assert_eq!(
+            Some(4440),
+            prepared.streams.speeds.map(|speeds| speeds.len())
+        );
+        assert!(prepared.stops.is_some());
+    }
+}
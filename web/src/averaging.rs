@@ -0,0 +1,125 @@
+use crate::heart_rate_drift::Seconds;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// How the samples in a segment are averaged
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Averaging {
+    /// Every sample counts the same, however far apart they are. This is how drift has always
+    /// been calculated, so it stays the default to keep results comparable with old ones.
+    #[default]
+    Unweighted,
+    /// Each sample holds its value until the next one
+    StepHold,
+    /// Values are joined by straight lines between samples and the area under them is averaged
+    Trapezoidal,
+}
+
+/// Averages `(time, value)` pairs, which must be in time order. With smart recording samples
+/// can be anywhere from 1 to 10 seconds apart, so the time weighted methods stop a burst of
+/// dense samples from dominating the average.
+pub fn average(samples: &[(Seconds, f64)], averaging: Averaging) -> Option<f64> {
+    let mut running = RunningAverage::new(averaging);
+    for &(time, value) in samples {
+        running.push(time, value);
+    }
+    running.average()
+}
+
+/// `average`, taking one sample at a time so the samples don't have to be kept
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RunningAverage {
+    averaging: Averaging,
+    count: usize,
+    total: f64,
+    /// Area under the samples so far, for the time weighted methods
+    area: f64,
+    first: Option<Seconds>,
+    previous: Option<(Seconds, f64)>,
+}
+
+impl RunningAverage {
+    pub fn new(averaging: Averaging) -> Self {
+        Self {
+            averaging,
+            count: 0,
+            total: 0.0,
+            area: 0.0,
+            first: None,
+            previous: None,
+        }
+    }
+
+    /// Adds a sample, which must not be earlier than the one before it
+    pub fn push(&mut self, time: Seconds, value: f64) {
+        if let Some((start, from)) = self.previous {
+            let elapsed = time - start;
+            self.area += match self.averaging {
+                Averaging::StepHold => from * elapsed,
+                _ => (from + value) / 2.0 * elapsed,
+            };
+        }
+        self.first.get_or_insert(time);
+        self.previous = Some((time, value));
+        self.total += value;
+        self.count += 1;
+    }
+
+    pub fn average(&self) -> Option<f64> {
+        let (first, (last, _)) = (self.first?, self.previous?);
+        let span = last - first;
+        if self.averaging == Averaging::Unweighted || span <= 0.0 {
+            Some(self.total / self.count as f64)
+        } else {
+            Some(self.area / span)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two minutes at 100, sampled every 10 seconds, then ten seconds at 200 sampled every second
+    fn irregular_samples() -> Vec<(Seconds, f64)> {
+        (0..=12)
+            .map(|step| (f64::from(step * 10), 100.0))
+            .chain((121..=130).map(|time| (f64::from(time), 200.0)))
+            .collect()
+    }
+
+    #[test]
+    fn test_no_samples_has_no_average() {
+        assert_eq!(None, average(&[], Averaging::Trapezoidal));
+    }
+
+    #[test]
+    fn test_one_sample_is_its_own_average() {
+        assert_eq!(Some(150.0), average(&[(10.0, 150.0)], Averaging::StepHold));
+    }
+
+    #[test]
+    fn test_unweighted_average_counts_every_sample_equally() {
+        assert_eq!(
+            Some(3300.0 / 23.0),
+            average(&irregular_samples(), Averaging::Unweighted)
+        );
+    }
+
+    #[test]
+    fn test_step_hold_weights_by_time_until_the_next_sample() {
+        assert_eq!(
+            Some((121.0 * 100.0 + 9.0 * 200.0) / 130.0),
+            average(&irregular_samples(), Averaging::StepHold)
+        );
+    }
+
+    #[test]
+    fn test_trapezoidal_joins_samples_with_lines() {
+        assert_eq!(
+            Some(150.0),
+            average(&[(0.0, 100.0), (10.0, 200.0)], Averaging::Trapezoidal)
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use crate::averaging::{average, Averaging};
+use crate::heart_rate_drift::Seconds;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Percentage of resampled drifts the interval covers
+const CONFIDENCE_LEVEL: f64 = 95.0;
+
+/// Whether and how to bootstrap a confidence interval for the drift
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BootstrapOptions {
+    /// Resample the heart rate this many times to put a 95% confidence interval on the drift.
+    /// 1000 is plenty
+    #[arg(long)]
+    pub bootstrap: Option<usize>,
+    /// Samples per resampled block. Heart rate follows on from one second to the next, so
+    /// resampling single samples would make the interval far too narrow
+    #[arg(long, default_value_t = BootstrapOptions::default().block_length)]
+    pub block_length: usize,
+    /// Seed for the resampling, so the same activity always gives the same interval
+    #[arg(long, default_value_t = BootstrapOptions::default().seed)]
+    pub seed: u64,
+}
+
+impl Default for BootstrapOptions {
+    fn default() -> Self {
+        Self {
+            bootstrap: None,
+            block_length: 60,
+            seed: 1,
+        }
+    }
+}
+
+/// A small seedable generator (SplitMix64). Good enough for resampling, and it keeps results
+/// the same between the command line, wasm and the tests.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 up to but not including `bound`, which must be positive
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfidenceInterval {
+    /// Percentage of resampled drifts between `lower` and `upper`
+    pub level: f64,
+    pub lower: f64,
+    pub upper: f64,
+    /// Half the width of the interval, for showing the drift as "4.6% ± 0.8%"
+    pub margin: f64,
+}
+
+/// Moving block bootstrap of the drift between the `(time, heart rate)` samples of the first and
+/// last segments. Each resample rebuilds both segments from randomly placed blocks of
+/// consecutive samples and averages the blocks. None when bootstrapping is off or a segment has
+/// no samples.
+pub fn drift_interval(
+    first: &[(Seconds, f64)],
+    last: &[(Seconds, f64)],
+    averaging: Averaging,
+    options: &BootstrapOptions,
+) -> Option<ConfidenceInterval> {
+    let resamples = options.bootstrap.filter(|&resamples| resamples > 0)?;
+    if first.is_empty() || last.is_empty() {
+        return None;
+    }
+    let mut rng = Rng::new(options.seed);
+    let block_length = options.block_length.max(1);
+
+    let mut drifts: Vec<f64> = (0..resamples)
+        .map(|_| {
+            let first = resampled_average(first, block_length, averaging, &mut rng);
+            let last = resampled_average(last, block_length, averaging, &mut rng);
+            (last - first) / first * 100.0
+        })
+        .collect();
+    drifts.sort_by(f64::total_cmp);
+
+    let tail = (100.0 - CONFIDENCE_LEVEL) / 200.0;
+    let percentile =
+        |fraction: f64| drifts[((drifts.len() - 1) as f64 * fraction).round() as usize];
+    let (lower, upper) = (percentile(tail), percentile(1.0 - tail));
+    Some(ConfidenceInterval {
+        level: CONFIDENCE_LEVEL,
+        lower,
+        upper,
+        margin: (upper - lower) / 2.0,
+    })
+}
+
+/// Average of enough random blocks to cover as many samples as the segment has
+fn resampled_average(
+    samples: &[(Seconds, f64)],
+    block_length: usize,
+    averaging: Averaging,
+    rng: &mut Rng,
+) -> f64 {
+    let block_length = block_length.min(samples.len());
+    let starts = samples.len() - block_length + 1;
+    let blocks = samples.len().div_ceil(block_length);
+    let total: f64 = (0..blocks)
+        .map(|_| {
+            let start = rng.below(starts);
+            average(&samples[start..start + block_length], averaging).unwrap_or_default()
+        })
+        .sum();
+    total / blocks as f64
+}
+
+impl fmt::Display for ConfidenceInterval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "± {:.2}% ({:.0}% confidence, {:.2}% to {:.2}%)",
+            self.margin, self.level, self.lower, self.upper
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heart_rate_drift::values_every_second;
+
+    fn options(bootstrap: Option<usize>) -> BootstrapOptions {
+        BootstrapOptions {
+            bootstrap,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_same_seed_gives_the_same_numbers() {
+        let mut first = Rng::new(42);
+        let mut second = Rng::new(42);
+
+        assert_eq!(
+            (0..5).map(|_| first.below(100)).collect::<Vec<_>>(),
+            (0..5).map(|_| second.below(100)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_steady_heart_rate_has_no_uncertainty() {
+        let first = values_every_second(0, 600, |_| 100.0);
+        let last = values_every_second(600, 1200, |_| 105.0);
+
+        let interval =
+            drift_interval(&first, &last, Averaging::Trapezoidal, &options(Some(200))).unwrap();
+
+        assert_eq!(5.0, interval.lower);
+        assert_eq!(5.0, interval.upper);
+        assert_eq!(0.0, interval.margin);
+    }
+
+    #[test]
+    fn test_noisy_heart_rate_gives_an_interval_around_the_drift() {
+        let noise = |time: i32| f64::from((time * 7919) % 11) - 5.0;
+        let first = values_every_second(0, 600, |time| 140.0 + noise(time));
+        let last = values_every_second(600, 1200, |time| 147.0 + noise(time));
+
+        let interval =
+            drift_interval(&first, &last, Averaging::Unweighted, &options(Some(500))).unwrap();
+
+        assert!(interval.lower < 5.0 && 5.0 < interval.upper);
+        assert!(interval.margin > 0.0);
+        assert_eq!(
+            Some(interval),
+            drift_interval(&first, &last, Averaging::Unweighted, &options(Some(500)))
+        );
+    }
+
+    #[test]
+    fn test_no_interval_unless_asked_for() {
+        let first = values_every_second(0, 600, |_| 100.0);
+
+        assert_eq!(
+            None,
+            drift_interval(&first, &first, Averaging::Trapezoidal, &options(None))
+        );
+    }
+}
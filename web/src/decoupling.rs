@@ -0,0 +1,438 @@
+use crate::averaging::average;
+use crate::heart_rate_drift::{
+    compared_windows, BeatsPerMinute, DriftSettings, HeartRateDriftError, Seconds,
+};
+use crate::protocol::Segment;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Normalized power smooths power over a rolling window this many seconds long
+const NORMALIZED_POWER_WINDOW: i32 = 30;
+
+/// What the heart rate is compared against
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DecouplingMode {
+    /// Heart rate drift alone, assuming the pace was held steady
+    #[default]
+    HeartRate,
+    /// Pa:HR, using speed. For running
+    Pace,
+    /// Pw:HR, using watts. For cycling
+    Power,
+    /// Heart rate against metres climbed per hour, from altitude. For tests done as a
+    /// sustained climb, like ski mountaineering or hiking
+    Vertical,
+}
+
+impl DecouplingMode {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DecouplingMode::HeartRate => "Heart rate drift",
+            DecouplingMode::Pace => "Pace:HR decoupling",
+            DecouplingMode::Power => "Power:HR decoupling",
+            DecouplingMode::Vertical => "Vertical:HR decoupling",
+        }
+    }
+
+    /// Describes a target output for the next test in the units athletes think in
+    pub fn describe_output(&self, output: f64) -> String {
+        match self {
+            DecouplingMode::Pace if output > 0.0 => {
+                let seconds_per_km = (1000.0 / output).round() as i32;
+                format!(
+                    "Aim for a pace of {}:{:02} per km",
+                    seconds_per_km / 60,
+                    seconds_per_km % 60
+                )
+            }
+            DecouplingMode::Power => format!("Aim for {output:.0} watts"),
+            DecouplingMode::Vertical => format!("Aim to climb {output:.0} metres an hour"),
+            _ => String::new(),
+        }
+    }
+}
+
+/// How the output is averaged over a segment
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputAverage {
+    #[default]
+    Mean,
+    /// Normalized power, which weights hard surges the way the body feels them
+    Normalized,
+}
+
+/// What heart rate is compared against, and how that output is averaged and smoothed
+#[derive(clap::Args, Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DecouplingOptions {
+    /// What to compare heart rate against
+    #[arg(long, value_enum, default_value_t = DecouplingOptions::default().mode)]
+    pub mode: DecouplingMode,
+    /// Use normalized power instead of average power in power mode
+    #[arg(long)]
+    pub normalized_power: bool,
+    /// Seconds the ascent rate is measured over in vertical mode. Altitude only changes by a
+    /// metre or so between samples, so shorter than this is mostly noise
+    #[arg(long, default_value_t = DecouplingOptions::default().ascent_smoothing)]
+    pub ascent_smoothing: i32,
+}
+
+impl Default for DecouplingOptions {
+    fn default() -> Self {
+        Self {
+            mode: DecouplingMode::HeartRate,
+            normalized_power: false,
+            ascent_smoothing: 60,
+        }
+    }
+}
+
+impl DecouplingOptions {
+    pub fn output_average(&self) -> OutputAverage {
+        if self.mode == DecouplingMode::Power && self.normalized_power {
+            OutputAverage::Normalized
+        } else {
+            OutputAverage::Mean
+        }
+    }
+}
+
+/// Heart rate alongside the output that produced it: speed in metres per second for Pa:HR, or
+/// watts for Pw:HR
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct EffortAtTime {
+    pub heart_rate: BeatsPerMinute,
+    pub output: f64,
+    pub time: Seconds,
+}
+
+/// Averages for one segment, and the efficiency factor (output per heart beat) they give
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentEfficiency {
+    pub average_heart_rate: f64,
+    pub average_output: f64,
+    pub efficiency_factor: f64,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecouplingResult {
+    pub first_segment: SegmentEfficiency,
+    pub last_segment: SegmentEfficiency,
+    /// Percentage of efficiency lost from the first segment to the last. Positive means the
+    /// heart rate rose (or output fell) over the test.
+    pub decoupling: f64,
+}
+
+/// Aerobic decoupling, the way Training Peaks calculates Pa:HR and Pw:HR
+pub trait Decoupling {
+    fn decoupling(
+        &self,
+        settings: &DriftSettings,
+        output_average: OutputAverage,
+    ) -> Result<DecouplingResult, HeartRateDriftError>;
+}
+
+impl Decoupling for Vec<EffortAtTime> {
+    fn decoupling(
+        &self,
+        settings: &DriftSettings,
+        output_average: OutputAverage,
+    ) -> Result<DecouplingResult, HeartRateDriftError> {
+        let last_time = self
+            .iter()
+            .map(|sample| sample.time)
+            .reduce(f64::max)
+            .unwrap_or_default();
+        let (first_window, last_window) = compared_windows(&settings.protocol, last_time)?;
+
+        let first_segment = segment_efficiency(self, &first_window, settings, output_average)
+            .ok_or(HeartRateDriftError::NotEnoughSamples)?;
+        let last_segment = segment_efficiency(self, &last_window, settings, output_average)
+            .ok_or(HeartRateDriftError::NotEnoughSamples)?;
+
+        if first_segment.efficiency_factor <= 0.0 {
+            return Err(HeartRateDriftError::NoOutput);
+        }
+
+        let decoupling = ((first_segment.efficiency_factor - last_segment.efficiency_factor)
+            / first_segment.efficiency_factor)
+            * 100.0;
+        Ok(DecouplingResult {
+            first_segment,
+            last_segment,
+            decoupling,
+        })
+    }
+}
+
+fn segment_efficiency(
+    samples: &[EffortAtTime],
+    window: &Segment,
+    settings: &DriftSettings,
+    output_average: OutputAverage,
+) -> Option<SegmentEfficiency> {
+    let in_window: Vec<&EffortAtTime> = samples
+        .iter()
+        .filter(|sample| window.contains(sample.time))
+        .collect();
+    let heart_rates: Vec<(Seconds, f64)> = in_window
+        .iter()
+        .map(|sample| (sample.time, f64::from(sample.heart_rate)))
+        .collect();
+    let outputs: Vec<(Seconds, f64)> = in_window
+        .iter()
+        .map(|sample| (sample.time, sample.output))
+        .collect();
+
+    let average_heart_rate = average(&heart_rates, settings.averaging)?;
+    let average_output = match output_average {
+        OutputAverage::Mean => average(&outputs, settings.averaging)?,
+        OutputAverage::Normalized => normalized_power(&in_window),
+    };
+    let efficiency_factor = if average_heart_rate > 0.0 {
+        average_output / average_heart_rate
+    } else {
+        0.0
+    };
+
+    Some(SegmentEfficiency {
+        average_heart_rate,
+        average_output,
+        efficiency_factor,
+    })
+}
+
+/// The fourth root of the mean of the fourth powers of the 30 second rolling average power
+fn normalized_power(samples: &[&EffortAtTime]) -> f64 {
+    let mut window_start = 0;
+    let mut window_total = 0.0;
+    let mut fourth_powers = 0.0;
+
+    for (index, sample) in samples.iter().enumerate() {
+        window_total += sample.output;
+        while samples[window_start].time <= sample.time - f64::from(NORMALIZED_POWER_WINDOW) {
+            window_total -= samples[window_start].output;
+            window_start += 1;
+        }
+        let rolling_average = window_total / (index + 1 - window_start) as f64;
+        fourth_powers += rolling_average.powi(4);
+    }
+
+    (fourth_powers / samples.len() as f64).powf(0.25)
+}
+
+pub fn combine_effort_with_time(
+    heart_rates: &[BeatsPerMinute],
+    outputs: &[f64],
+    times: &[Seconds],
+) -> Vec<EffortAtTime> {
+    heart_rates
+        .iter()
+        .copied()
+        .zip(outputs.iter().copied())
+        .zip(times.iter().copied())
+        .map(|((heart_rate, output), time)| EffortAtTime {
+            heart_rate,
+            output,
+            time,
+        })
+        .collect()
+}
+
+/// Speed in metres per second from Strava's cumulative `distance` stream, for activities that
+/// don't have `velocity_smooth`. The first sample takes the speed of the second.
+pub fn speed_from_distance(distances: &[f64], times: &[Seconds]) -> Vec<f64> {
+    let mut speeds: Vec<f64> = distances
+        .windows(2)
+        .zip(times.windows(2))
+        .map(|(distance, time)| {
+            let elapsed = time[1] - time[0];
+            if elapsed > 0.0 {
+                (distance[1] - distance[0]) / elapsed
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    if let Some(&first) = speeds.first() {
+        speeds.insert(0, first);
+    }
+    speeds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WARM_UP_LIMIT: Seconds = 900.0;
+    const FIRST_SEGMENT_LIMIT: Seconds = 2700.0;
+
+    #[test]
+    fn test_combine_effort_with_time() {
+        let actual = combine_effort_with_time(&[150, 151], &[3.0, 3.5], &[0.0, 1.0]);
+
+        assert_eq!(
+            vec![
+                EffortAtTime {
+                    heart_rate: 150,
+                    output: 3.0,
+                    time: 0.0
+                },
+                EffortAtTime {
+                    heart_rate: 151,
+                    output: 3.5,
+                    time: 1.0
+                }
+            ],
+            actual
+        );
+    }
+
+    #[test]
+    fn test_no_samples_is_not_enough_for_decoupling() {
+        let samples: Vec<EffortAtTime> = vec![];
+
+        assert_eq!(
+            Err(HeartRateDriftError::NotEnoughSamples),
+            samples.decoupling(&DriftSettings::default(), OutputAverage::Mean)
+        );
+    }
+
+    #[test]
+    fn test_same_heart_rate_at_slower_pace_is_decoupling() {
+        let samples = vec![
+            EffortAtTime {
+                heart_rate: 128,
+                output: 4.0,
+                time: WARM_UP_LIMIT,
+            },
+            EffortAtTime {
+                heart_rate: 128,
+                output: 3.0,
+                time: FIRST_SEGMENT_LIMIT,
+            },
+        ];
+
+        let result = samples
+            .decoupling(&DriftSettings::default(), OutputAverage::Mean)
+            .unwrap();
+
+        assert_eq!(0.03125, result.first_segment.efficiency_factor);
+        assert_eq!(25.0, result.decoupling);
+    }
+
+    #[test]
+    fn test_higher_heart_rate_at_faster_pace_is_not_decoupling() {
+        let samples = vec![
+            EffortAtTime {
+                heart_rate: 100,
+                output: 3.0,
+                time: WARM_UP_LIMIT,
+            },
+            EffortAtTime {
+                heart_rate: 110,
+                output: 3.3,
+                time: FIRST_SEGMENT_LIMIT,
+            },
+        ];
+
+        let result = samples
+            .decoupling(&DriftSettings::default(), OutputAverage::Mean)
+            .unwrap();
+
+        assert!(result.decoupling.abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_standing_still_in_the_first_segment_is_an_error() {
+        let samples = vec![
+            EffortAtTime {
+                heart_rate: 100,
+                output: 0.0,
+                time: WARM_UP_LIMIT,
+            },
+            EffortAtTime {
+                heart_rate: 100,
+                output: 3.0,
+                time: FIRST_SEGMENT_LIMIT,
+            },
+        ];
+
+        assert_eq!(
+            Err(HeartRateDriftError::NoOutput),
+            samples.decoupling(&DriftSettings::default(), OutputAverage::Mean)
+        );
+    }
+
+    #[test]
+    fn test_speed_from_distance() {
+        let speeds = speed_from_distance(&[0.0, 3.0, 9.0, 9.0], &[0.0, 1.0, 3.0, 3.0]);
+
+        assert_eq!(vec![3.0, 3.0, 3.0, 0.0], speeds);
+    }
+
+    #[test]
+    fn test_normalized_power_of_steady_power_is_the_average() {
+        let samples: Vec<EffortAtTime> = (0..120)
+            .map(|time| EffortAtTime {
+                heart_rate: 140,
+                output: 200.0,
+                time: f64::from(time),
+            })
+            .collect();
+        let in_window: Vec<&EffortAtTime> = samples.iter().collect();
+
+        assert!((normalized_power(&in_window) - 200.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_normalized_power_weights_surges() {
+        let samples: Vec<EffortAtTime> = (0..600)
+            .map(|time| EffortAtTime {
+                heart_rate: 140,
+                output: if (time / 60) % 2 == 0 { 300.0 } else { 100.0 },
+                time: f64::from(time),
+            })
+            .collect();
+        let in_window: Vec<&EffortAtTime> = samples.iter().collect();
+
+        assert!(normalized_power(&in_window) > 200.0);
+    }
+
+    #[test]
+    fn test_power_decoupling_with_normalized_power() {
+        let samples = vec![
+            EffortAtTime {
+                heart_rate: 128,
+                output: 200.0,
+                time: WARM_UP_LIMIT,
+            },
+            EffortAtTime {
+                heart_rate: 128,
+                output: 150.0,
+                time: FIRST_SEGMENT_LIMIT,
+            },
+        ];
+
+        let result = samples
+            .decoupling(&DriftSettings::default(), OutputAverage::Normalized)
+            .unwrap();
+
+        assert_eq!(200.0, result.first_segment.average_output);
+        assert_eq!(25.0, result.decoupling);
+    }
+
+    #[test]
+    fn test_normalized_power_is_only_used_in_power_mode() {
+        let options = DecouplingOptions {
+            mode: DecouplingMode::Pace,
+            normalized_power: true,
+            ..Default::default()
+        };
+
+        assert_eq!(OutputAverage::Mean, options.output_average());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::heart_rate_drift::{HeartRateAtTime, Seconds};
+use crate::protocol::{DriftProtocol, SegmentLength};
+use crate::report::minutes_and_seconds;
+use serde::Serialize;
+use std::fmt;
+
+/// How far apart the candidate start times are, in seconds
+const STEP: i32 = 60;
+/// Seconds at the start of the first segment compared against the whole segment, to see
+/// whether heart rate had settled by the end of the warm-up
+const SETTLING: i32 = 300;
+
+/// Where the test most likely sits inside a longer activity
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DetectedWindow {
+    /// The protocol moved to start at the detected offset, ready to calculate drift with
+    pub protocol: DriftProtocol,
+    /// `pace_variation` plus `heart_rate_settling`. Lower is better, and under 5 or so looks
+    /// like a steady test.
+    pub score: f64,
+    /// Coefficient of variation, as a percentage, of the speed or power over the segments.
+    /// Missing when there was no output stream to check.
+    pub pace_variation: Option<f64>,
+    /// Percentage difference between the first few minutes of the first segment and the whole
+    /// of it. Large when heart rate was still climbing after the warm-up.
+    pub heart_rate_settling: f64,
+}
+
+/// Slides the protocol along the activity a minute at a time and picks the start where the
+/// segments were run most steadily. `outputs` is speed or power, one value per sample, and
+/// when it's missing only heart rate is looked at.
+///
+/// None when the protocol has no fixed length to slide, or the activity is shorter than the
+/// test.
+pub fn detect_window(
+    samples: &[HeartRateAtTime],
+    outputs: Option<&[f64]>,
+    protocol: &DriftProtocol,
+) -> Option<DetectedWindow> {
+    let SegmentLength::Fixed(length) = protocol.segment_length else {
+        return None;
+    };
+    if !protocol.is_valid() {
+        return None;
+    }
+    let tested = length * protocol.segments as i32;
+    let last_time = samples
+        .iter()
+        .map(|sample| sample.time)
+        .reduce(f64::max)?
+        .floor() as i32;
+    let latest_start = last_time + 1 - protocol.warm_up - tested;
+
+    (0..=latest_start.max(-1))
+        .step_by(STEP as usize)
+        .filter_map(|start| {
+            let from = start + protocol.warm_up;
+            candidate(samples, outputs, from, from + tested, length).map(
+                |(pace_variation, heart_rate_settling)| DetectedWindow {
+                    protocol: DriftProtocol {
+                        start,
+                        ..protocol.clone()
+                    },
+                    score: pace_variation.unwrap_or_default() + heart_rate_settling,
+                    pace_variation,
+                    heart_rate_settling,
+                },
+            )
+        })
+        .reduce(|best, window| {
+            if window.score < best.score {
+                window
+            } else {
+                best
+            }
+        })
+}
+
+/// Pace variation and heart rate settling for segments running from `from` to `to`
+fn candidate(
+    samples: &[HeartRateAtTime],
+    outputs: Option<&[f64]>,
+    from: i32,
+    to: i32,
+    length: i32,
+) -> Option<(Option<f64>, f64)> {
+    let inside = |time: Seconds, end: i32| time >= f64::from(from) && time < f64::from(end);
+    let first_segment = mean(
+        samples
+            .iter()
+            .filter(|sample| inside(sample.time, from + length))
+            .map(|sample| f64::from(sample.heart_rate)),
+    )?;
+    let settling = mean(
+        samples
+            .iter()
+            .filter(|sample| inside(sample.time, from + SETTLING.min(length / 2)))
+            .map(|sample| f64::from(sample.heart_rate)),
+    )?;
+    if first_segment <= 0.0 {
+        return None;
+    }
+    let heart_rate_settling = 100.0 * (first_segment - settling).abs() / first_segment;
+
+    let pace_variation = match outputs {
+        Some(outputs) => {
+            let values: Vec<f64> = samples
+                .iter()
+                .zip(outputs)
+                .filter(|(sample, _)| inside(sample.time, to))
+                .map(|(_, &output)| output)
+                .collect();
+            let average = mean(values.iter().copied())?;
+            if average <= 0.0 {
+                return None;
+            }
+            let variance = mean(values.iter().map(|value| (value - average).powi(2)))?;
+            Some(100.0 * variance.sqrt() / average)
+        }
+        None => None,
+    };
+    Some((pace_variation, heart_rate_settling))
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (total, count) = values.fold((0.0, 0), |(total, count), value| (total + value, count + 1));
+    (count > 0).then(|| total / f64::from(count))
+}
+
+impl fmt::Display for DetectedWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Detected the test starting at {} (score {:.1}",
+            minutes_and_seconds(self.protocol.start),
+            self.score
+        )?;
+        if let Some(pace_variation) = self.pace_variation {
+            write!(f, ", pace varied {pace_variation:.1}%")?;
+        }
+        write!(
+            f,
+            ", heart rate settled within {:.1}%)",
+            self.heart_rate_settling
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 20 minute jog, the test at a steady 3 m/s, then a jog home with stops
+    fn commute_test_and_jog_home() -> (Vec<HeartRateAtTime>, Vec<f64>) {
+        let mut samples = Vec::new();
+        let mut speeds = Vec::new();
+        for time in 0..9000 {
+            let (heart_rate, speed) = match time {
+                0..1200 => (110 + time % 30, 2.0 + f64::from(time % 120) / 60.0),
+                1200..2100 => (120 + (time - 1200) / 45, 3.0),
+                2100..5700 => (140 + (time - 2100) / 600, 3.0),
+                _ => (120, if time % 300 < 60 { 0.0 } else { 2.5 }),
+            };
+            samples.push(HeartRateAtTime {
+                heart_rate,
+                time: f64::from(time),
+            });
+            speeds.push(speed);
+        }
+        (samples, speeds)
+    }
+
+    #[test]
+    fn test_finds_the_test_inside_a_longer_run() {
+        let (samples, speeds) = commute_test_and_jog_home();
+
+        let detected =
+            detect_window(&samples, Some(&speeds), &DriftProtocol::uphill_athlete()).unwrap();
+
+        assert_eq!(1200, detected.protocol.start);
+        assert_eq!(Some(0.0), detected.pace_variation);
+    }
+
+    #[test]
+    fn test_heart_rate_alone_is_enough() {
+        let (samples, _) = commute_test_and_jog_home();
+
+        let detected = detect_window(&samples, None, &DriftProtocol::uphill_athlete()).unwrap();
+
+        assert_eq!(None, detected.pace_variation);
+        assert_eq!(detected.heart_rate_settling, detected.score);
+    }
+
+    #[test]
+    fn test_split_protocols_cannot_be_detected() {
+        let (samples, speeds) = commute_test_and_jog_home();
+
+        assert_eq!(
+            None,
+            detect_window(&samples, Some(&speeds), &DriftProtocol::training_peaks())
+        );
+    }
+
+    #[test]
+    fn test_activity_shorter_than_the_test_has_no_window() {
+        let samples: Vec<HeartRateAtTime> = (0..3000)
+            .map(|time| HeartRateAtTime {
+                heart_rate: 140,
+                time: f64::from(time),
+            })
+            .collect();
+
+        assert_eq!(
+            None,
+            detect_window(&samples, None, &DriftProtocol::uphill_athlete())
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use crate::heart_rate_drift::Seconds;
+use crate::rr::CleanBeats;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// α1 drops through this at the aerobic threshold
+/// (Rogers et al. 2021, https://doi.org/10.3389/fphys.2020.596567)
+pub const AEROBIC_THRESHOLD_ALPHA1: f64 = 0.75;
+/// Box sizes, in beats, that short-term scaling is measured over
+const SMALLEST_BOX: usize = 4;
+const LARGEST_BOX: usize = 16;
+/// Below this many beats there are too few of the largest boxes to average over
+const MIN_BEATS: usize = 50;
+
+/// The windows DFA α1 is calculated over, and how many artifacts a window can have
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DfaOptions {
+    /// Seconds of beats each DFA α1 value is calculated from
+    #[arg(long, default_value_t = DfaOptions::default().dfa_window)]
+    pub dfa_window: i32,
+    /// Seconds the DFA α1 window moves between values
+    #[arg(long, default_value_t = DfaOptions::default().dfa_step)]
+    pub dfa_step: i32,
+    /// Leave out DFA α1 windows with more than this percentage of their beats removed as
+    /// artifacts, since α1 is thrown off by them
+    #[arg(long, default_value_t = DfaOptions::default().max_artifacts)]
+    pub max_artifacts: f64,
+}
+
+impl Default for DfaOptions {
+    fn default() -> Self {
+        Self {
+            dfa_window: 120,
+            dfa_step: 5,
+            max_artifacts: 5.0,
+        }
+    }
+}
+
+/// α1 over time. `alpha1s[i]` and `heart_rates[i]` are from the window ending at `times[i]`.
+/// Kept as columns so they can go straight to a chart.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alpha1Series {
+    pub times: Vec<Seconds>,
+    pub alpha1s: Vec<f64>,
+    /// Heart rate from the beats in each window, in beats per minute
+    pub heart_rates: Vec<f64>,
+}
+
+/// The aerobic threshold from heart rate variability, to check against the one from drift
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DfaAlpha1 {
+    pub series: Alpha1Series,
+    /// Percentage of all the beats that were removed as ectopic beats or artifacts
+    pub artifacts: f64,
+    /// Heart rate where α1, fitted as a line against heart rate, crosses 0.75. None when α1
+    /// doesn't fall as heart rate rises.
+    pub threshold_heart_rate: Option<f64>,
+    /// The crossing is outside the heart rates in the activity, so the line was extended to
+    /// reach it and it's only a rough guide
+    pub extrapolated: bool,
+}
+
+/// Short-term scaling exponent of detrended fluctuation analysis over RR intervals in
+/// milliseconds. Around 1 at rest and easy effort, falling towards 0.5 as the effort gets hard.
+pub fn alpha1(intervals: &[f64]) -> Option<f64> {
+    if intervals.len() < MIN_BEATS {
+        return None;
+    }
+    let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+    let integrated: Vec<f64> = intervals
+        .iter()
+        .scan(0.0, |total, interval| {
+            *total += interval - mean;
+            Some(*total)
+        })
+        .collect();
+
+    let mut points = Vec::new();
+    for size in SMALLEST_BOX..=LARGEST_BOX {
+        let squares: f64 = integrated.chunks_exact(size).map(detrended_squares).sum();
+        let boxes = integrated.len() / size;
+        let fluctuation = (squares / (boxes * size) as f64).sqrt();
+        if fluctuation <= 0.0 {
+            return None;
+        }
+        points.push(((size as f64).ln(), fluctuation.ln()));
+    }
+    line(&points).map(|(slope, _)| slope)
+}
+
+/// Squared distance of each value from the straight line through them
+fn detrended_squares(values: &[f64]) -> f64 {
+    let points: Vec<(f64, f64)> = values
+        .iter()
+        .enumerate()
+        .map(|(index, &value)| (index as f64, value))
+        .collect();
+    let Some((slope, intercept)) = line(&points) else {
+        return 0.0;
+    };
+    points
+        .iter()
+        .map(|(x, y)| (y - (intercept + slope * x)).powi(2))
+        .sum()
+}
+
+/// Least squares slope and intercept, when the points aren't all at one x
+fn line(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let count = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+    let (covariance, variance) =
+        points
+            .iter()
+            .fold((0.0, 0.0), |(covariance, variance), (x, y)| {
+                (
+                    covariance + (x - mean_x) * (y - mean_y),
+                    variance + (x - mean_x).powi(2),
+                )
+            });
+    (variance > 0.0).then(|| {
+        let slope = covariance / variance;
+        (slope, mean_y - slope * mean_x)
+    })
+}
+
+/// α1 over `dfa_window` second windows of the cleaned beats, moving `dfa_step` seconds at a
+/// time. Windows with too many artifacts or too few beats are left out.
+pub fn alpha1_series(clean: &CleanBeats, options: &DfaOptions) -> Alpha1Series {
+    let mut series = Alpha1Series::default();
+    let (Some(first), Some(last)) = (clean.beats.first(), clean.beats.last()) else {
+        return series;
+    };
+    let window = f64::from(options.dfa_window);
+    if window <= 0.0 || options.dfa_step <= 0 {
+        return series;
+    }
+
+    let mut end = first.time + window;
+    while end <= last.time {
+        let from = clean.beats.partition_point(|beat| beat.time < end - window);
+        let to = clean.beats.partition_point(|beat| beat.time < end);
+        let intervals: Vec<f64> = clean.beats[from..to]
+            .iter()
+            .map(|beat| beat.interval)
+            .collect();
+        if clean.artifacts_between(end - window, end) <= options.max_artifacts {
+            if let Some(alpha1) = alpha1(&intervals) {
+                let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+                series.times.push(end);
+                series.alpha1s.push(alpha1);
+                series.heart_rates.push(60_000.0 / mean);
+            }
+        }
+        end += f64::from(options.dfa_step);
+    }
+    series
+}
+
+impl DfaAlpha1 {
+    pub fn new(clean: &CleanBeats, options: &DfaOptions) -> Self {
+        let series = alpha1_series(clean, options);
+        let points: Vec<(f64, f64)> = series
+            .heart_rates
+            .iter()
+            .copied()
+            .zip(series.alpha1s.iter().copied())
+            .collect();
+        let threshold_heart_rate = line(&points)
+            .filter(|(slope, _)| *slope < 0.0)
+            .map(|(slope, intercept)| (AEROBIC_THRESHOLD_ALPHA1 - intercept) / slope);
+        let lowest = series.heart_rates.iter().copied().fold(f64::MAX, f64::min);
+        let highest = series.heart_rates.iter().copied().fold(f64::MIN, f64::max);
+
+        Self {
+            artifacts: clean.artifacts(),
+            extrapolated: threshold_heart_rate
+                .is_some_and(|heart_rate| heart_rate < lowest || heart_rate > highest),
+            threshold_heart_rate,
+            series,
+        }
+    }
+}
+
+impl fmt::Display for DfaAlpha1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "DFA α1 over {} windows ({:.1}% of beats removed as artifacts): ",
+            self.series.alpha1s.len(),
+            self.artifacts
+        )?;
+        match self.threshold_heart_rate {
+            Some(heart_rate) => {
+                write!(
+                    f,
+                    "crosses {AEROBIC_THRESHOLD_ALPHA1} at {heart_rate:.0} bpm"
+                )?;
+                if self.extrapolated {
+                    write!(f, ", extrapolated beyond the heart rates recorded")?;
+                }
+                Ok(())
+            }
+            None => write!(
+                f,
+                "doesn't fall as heart rate rises, so there's no crossing of {AEROBIC_THRESHOLD_ALPHA1}"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootstrap::Rng;
+    use crate::rr::{beats_from_intervals, clean_beats, RrOptions};
+
+    /// Uncorrelated noise around `mean` milliseconds
+    fn white_noise(rng: &mut Rng, count: usize, mean: f64) -> Vec<f64> {
+        (0..count)
+            .map(|_| mean + (rng.below(1001) as f64 - 500.0) / 50.0)
+            .collect()
+    }
+
+    #[test]
+    fn test_uncorrelated_intervals_have_alpha1_near_a_half() {
+        let intervals = white_noise(&mut Rng::new(1), 2000, 600.0);
+
+        let alpha1 = alpha1(&intervals).unwrap();
+
+        assert!((alpha1 - 0.5).abs() < 0.1, "{alpha1}");
+    }
+
+    #[test]
+    fn test_wandering_intervals_have_a_high_alpha1() {
+        let steps = white_noise(&mut Rng::new(2), 2000, 0.0);
+        let intervals: Vec<f64> = steps
+            .iter()
+            .scan(800.0, |interval, step| {
+                *interval += step / 5.0;
+                Some(*interval)
+            })
+            .collect();
+
+        assert!(alpha1(&intervals).unwrap() > 1.2);
+    }
+
+    #[test]
+    fn test_too_few_or_constant_intervals_have_no_alpha1() {
+        assert_eq!(None, alpha1(&[800.0; 20]));
+        assert_eq!(None, alpha1(&[800.0; 200]));
+    }
+
+    #[test]
+    fn test_series_skips_windows_full_of_artifacts() {
+        let mut intervals = white_noise(&mut Rng::new(3), 600, 600.0);
+        for interval in &mut intervals[300..330] {
+            *interval = 250.0;
+        }
+        let clean = clean_beats(&beats_from_intervals(&intervals), &RrOptions::default());
+
+        let series = alpha1_series(&clean, &DfaOptions::default());
+
+        assert!(!series.times.is_empty());
+        // Every window ending between these has all 30 artifacts in it
+        assert!(!series
+            .times
+            .iter()
+            .any(|time| (188.0..=300.0).contains(time)));
+    }
+
+    #[test]
+    fn test_threshold_is_where_alpha1_crosses_three_quarters() {
+        // Correlated beats at 100 bpm give way to uncorrelated ones at 150 bpm
+        let mut rng = Rng::new(4);
+        let steps = white_noise(&mut rng, 1200, 0.0);
+        let mut intervals: Vec<f64> = steps
+            .iter()
+            .scan(600.0, |interval, step| {
+                *interval = 600.0 + (*interval - 600.0) * 0.9 + step;
+                Some(*interval)
+            })
+            .collect();
+        intervals.extend(white_noise(&mut rng, 1200, 400.0));
+        let clean = clean_beats(&beats_from_intervals(&intervals), &RrOptions::default());
+
+        let dfa = DfaAlpha1::new(&clean, &DfaOptions::default());
+
+        let threshold = dfa.threshold_heart_rate.unwrap();
+        assert!((100.0..150.0).contains(&threshold), "{threshold}");
+        assert!(!dfa.extrapolated);
+    }
+}
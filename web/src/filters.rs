@@ -0,0 +1,410 @@
+use crate::heart_rate_drift::{HeartRateAtTime, HeartRateDriftError};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fmt;
+
+/// Scales the median absolute deviation to a standard deviation for normally distributed data
+const MAD_TO_STANDARD_DEVIATION: f64 = 1.4826;
+
+/// How many samples the starting heart rate is the median of
+const RATE_OF_CHANGE_START_SAMPLES: usize = 5;
+
+/// Removes samples that can't be real heart rate. Filters see only the samples that earlier
+/// filters kept, along with the matching cadence when the activity has it.
+pub trait HeartRateFilter {
+    fn name(&self) -> &'static str;
+    /// One entry per sample, true to keep it
+    fn keep(&self, samples: &[HeartRateAtTime], cadence: Option<&[f64]>) -> Vec<bool>;
+}
+
+/// Drops anything outside what a human heart can do, including the zeros sensors send when
+/// they lose contact
+pub struct PhysiologicalBounds {
+    pub min: i32,
+    pub max: i32,
+}
+
+impl Default for PhysiologicalBounds {
+    fn default() -> Self {
+        Self { min: 30, max: 230 }
+    }
+}
+
+impl HeartRateFilter for PhysiologicalBounds {
+    fn name(&self) -> &'static str {
+        "physiological bounds"
+    }
+
+    fn keep(&self, samples: &[HeartRateAtTime], _cadence: Option<&[f64]>) -> Vec<bool> {
+        samples
+            .iter()
+            .map(|sample| (self.min..=self.max).contains(&sample.heart_rate))
+            .collect()
+    }
+}
+
+/// Drops samples that jump further from the last good sample than a heart can change, like the
+/// spikes a chest strap gives before it's wet. The first sample could be a spike too, so the
+/// comparison starts from the median of the opening samples instead.
+pub struct RateOfChange {
+    pub max_bpm_per_second: f64,
+}
+
+impl Default for RateOfChange {
+    fn default() -> Self {
+        Self {
+            max_bpm_per_second: 5.0,
+        }
+    }
+}
+
+impl HeartRateFilter for RateOfChange {
+    fn name(&self) -> &'static str {
+        "rate of change"
+    }
+
+    fn keep(&self, samples: &[HeartRateAtTime], _cadence: Option<&[f64]>) -> Vec<bool> {
+        let Some(first) = samples.first() else {
+            return Vec::new();
+        };
+        let opening: Vec<f64> = samples
+            .iter()
+            .take(RATE_OF_CHANGE_START_SAMPLES)
+            .map(|sample| f64::from(sample.heart_rate))
+            .collect();
+        let mut last_good = (first.time, median(&opening));
+        samples
+            .iter()
+            .map(|sample| {
+                let (time, heart_rate) = last_good;
+                let elapsed = (sample.time - time).max(1.0);
+                let change = (f64::from(sample.heart_rate) - heart_rate).abs();
+                let keep = change / elapsed <= self.max_bpm_per_second;
+                if keep {
+                    last_good = (sample.time, f64::from(sample.heart_rate));
+                }
+                keep
+            })
+            .collect()
+    }
+}
+
+/// A Hampel filter: drops samples more than `threshold` standard deviations from the median of
+/// their neighbours, using the median absolute deviation so the outliers don't skew it
+pub struct Hampel {
+    /// Samples either side of the one being checked
+    pub half_window: usize,
+    pub threshold: f64,
+}
+
+impl Default for Hampel {
+    fn default() -> Self {
+        Self {
+            half_window: 7,
+            threshold: 3.0,
+        }
+    }
+}
+
+impl HeartRateFilter for Hampel {
+    fn name(&self) -> &'static str {
+        "hampel"
+    }
+
+    fn keep(&self, samples: &[HeartRateAtTime], _cadence: Option<&[f64]>) -> Vec<bool> {
+        let rates: Vec<f64> = samples
+            .iter()
+            .map(|sample| f64::from(sample.heart_rate))
+            .collect();
+        (0..rates.len())
+            .map(|index| {
+                let from = index.saturating_sub(self.half_window);
+                let to = (index + self.half_window + 1).min(rates.len());
+                let window = &rates[from..to];
+                let window_median = median(window);
+                let deviations: Vec<f64> = window
+                    .iter()
+                    .map(|rate| (rate - window_median).abs())
+                    .collect();
+                // Heart rate is recorded in whole beats, so a steady stretch has no deviation at
+                // all. Don't let that turn a one beat wobble into an outlier.
+                let sigma = (MAD_TO_STANDARD_DEVIATION * median(&deviations)).max(1.0);
+                (rates[index] - window_median).abs() <= self.threshold * sigma
+            })
+            .collect()
+    }
+}
+
+/// Drops stretches where the heart rate follows the cadence, which is what wrist sensors do
+/// when they lock onto arm swing. Strava reports running cadence per leg, so both the cadence
+/// and double it are checked.
+pub struct CadenceLock {
+    /// How close, in beats per minute, heart rate has to be to cadence to count as locked
+    pub tolerance: f64,
+    /// Seconds heart rate has to stay locked before it's removed
+    pub min_duration: i32,
+}
+
+impl Default for CadenceLock {
+    fn default() -> Self {
+        Self {
+            tolerance: 3.0,
+            min_duration: 60,
+        }
+    }
+}
+
+impl HeartRateFilter for CadenceLock {
+    fn name(&self) -> &'static str {
+        "cadence lock"
+    }
+
+    fn keep(&self, samples: &[HeartRateAtTime], cadence: Option<&[f64]>) -> Vec<bool> {
+        let Some(cadence) = cadence else {
+            return vec![true; samples.len()];
+        };
+        let locked: Vec<bool> = samples
+            .iter()
+            .zip(cadence)
+            .map(|(sample, &cadence)| {
+                let rate = f64::from(sample.heart_rate);
+                cadence > 0.0
+                    && ((rate - cadence).abs() <= self.tolerance
+                        || (rate - 2.0 * cadence).abs() <= self.tolerance)
+            })
+            .collect();
+
+        let mut keep = Vec::with_capacity(samples.len());
+        let mut start = 0;
+        for run in locked.chunk_by(|first, second| first == second) {
+            let end = start + run.len();
+            let too_long = run[0]
+                && samples[end - 1].time - samples[start].time >= f64::from(self.min_duration);
+            keep.extend(std::iter::repeat_n(!too_long, run.len()));
+            start = end;
+        }
+        keep
+    }
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+/// The filters that can be picked from the command line and wasm options
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilterKind {
+    Bounds,
+    RateOfChange,
+    Hampel,
+    CadenceLock,
+}
+
+impl FilterKind {
+    pub fn filter(&self) -> Box<dyn HeartRateFilter> {
+        match self {
+            FilterKind::Bounds => Box::new(PhysiologicalBounds::default()),
+            FilterKind::RateOfChange => Box::new(RateOfChange::default()),
+            FilterKind::Hampel => Box::new(Hampel::default()),
+            FilterKind::CadenceLock => Box::new(CadenceLock::default()),
+        }
+    }
+}
+
+/// How many samples each filter removed, in the order they ran
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FilterReport {
+    pub removed: Vec<(&'static str, usize)>,
+}
+
+impl fmt::Display for FilterReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts: Vec<String> = self
+            .removed
+            .iter()
+            .map(|(name, count)| format!("{name}: {count}"))
+            .collect();
+        write!(f, "Samples removed by {}", counts.join(", "))
+    }
+}
+
+/// Which samples survived the filters, so the other streams can be trimmed to match
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filtered {
+    pub keep: Vec<bool>,
+    pub report: FilterReport,
+}
+
+impl Filtered {
+    pub fn retain<T: Copy>(&self, values: &[T]) -> Vec<T> {
+        values
+            .iter()
+            .zip(&self.keep)
+            .filter_map(|(value, &keep)| keep.then_some(*value))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct FilterPipeline {
+    filters: Vec<Box<dyn HeartRateFilter>>,
+}
+
+impl FilterPipeline {
+    pub fn new(filters: Vec<Box<dyn HeartRateFilter>>) -> Self {
+        Self { filters }
+    }
+
+    pub fn from_kinds(kinds: &[FilterKind]) -> Self {
+        Self::new(kinds.iter().map(FilterKind::filter).collect())
+    }
+
+    /// Runs each filter over what the ones before it kept. `cadence` has to have a value for
+    /// every sample.
+    pub fn run(
+        &self,
+        samples: &[HeartRateAtTime],
+        cadence: Option<&[f64]>,
+    ) -> Result<Filtered, HeartRateDriftError> {
+        if let Some(cadence) = cadence.filter(|cadence| cadence.len() != samples.len()) {
+            return Err(HeartRateDriftError::LengthMismatch {
+                stream: "cadence",
+                expected: samples.len(),
+                found: cadence.len(),
+            });
+        }
+        let mut kept: Vec<usize> = (0..samples.len()).collect();
+        let mut report = FilterReport::default();
+
+        for filter in &self.filters {
+            let remaining: Vec<HeartRateAtTime> =
+                kept.iter().map(|&index| samples[index]).collect();
+            let remaining_cadence: Option<Vec<f64>> =
+                cadence.map(|cadence| kept.iter().map(|&index| cadence[index]).collect());
+            let keep = filter.keep(&remaining, remaining_cadence.as_deref());
+
+            let before = kept.len();
+            kept = kept
+                .into_iter()
+                .zip(keep)
+                .filter_map(|(index, keep)| keep.then_some(index))
+                .collect();
+            report.removed.push((filter.name(), before - kept.len()));
+        }
+
+        let mut keep = vec![false; samples.len()];
+        for index in kept {
+            keep[index] = true;
+        }
+        Ok(Filtered { keep, report })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heart_rate_drift::every_second;
+
+    #[test]
+    fn test_bounds_remove_zero_and_impossible_readings() {
+        let samples = every_second(&[0, 140, 250, 141]);
+
+        assert_eq!(
+            vec![false, true, false, true],
+            PhysiologicalBounds::default().keep(&samples, None)
+        );
+    }
+
+    #[test]
+    fn test_rate_of_change_removes_spikes_but_not_what_follows() {
+        let samples = every_second(&[140, 141, 190, 142, 143]);
+
+        assert_eq!(
+            vec![true, true, false, true, true],
+            RateOfChange::default().keep(&samples, None)
+        );
+    }
+
+    #[test]
+    fn test_rate_of_change_removes_a_spike_at_the_start() {
+        let samples = every_second(&[190, 140, 141, 142, 143]);
+
+        assert_eq!(
+            vec![false, true, true, true, true],
+            RateOfChange::default().keep(&samples, None)
+        );
+    }
+
+    #[test]
+    fn test_hampel_removes_an_outlier_among_steady_readings() {
+        let samples = every_second(&[140, 141, 140, 142, 175, 141, 140, 141, 142]);
+
+        let keep = Hampel::default().keep(&samples, None);
+
+        assert_eq!(
+            vec![4],
+            (0..9).filter(|&index| !keep[index]).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_cadence_lock_removes_long_locked_stretches() {
+        let mut heart_rates = vec![150; 30];
+        heart_rates.extend(vec![176; 90]);
+        let samples = every_second(&heart_rates);
+        let cadence = vec![88.0; 120];
+
+        let keep = CadenceLock::default().keep(&samples, Some(&cadence));
+
+        assert!(keep[..30].iter().all(|&keep| keep));
+        assert!(keep[30..].iter().all(|&keep| !keep));
+    }
+
+    #[test]
+    fn test_cadence_lock_does_nothing_without_cadence() {
+        let samples = every_second(&[176; 90]);
+
+        assert!(CadenceLock::default()
+            .keep(&samples, None)
+            .iter()
+            .all(|&keep| keep));
+    }
+
+    #[test]
+    fn test_pipeline_reports_what_each_filter_removed() {
+        let samples = every_second(&[0, 140, 141, 190, 142, 143]);
+        let pipeline = FilterPipeline::from_kinds(&[FilterKind::Bounds, FilterKind::RateOfChange]);
+
+        let filtered = pipeline.run(&samples, None).unwrap();
+
+        assert_eq!(vec![false, true, true, false, true, true], filtered.keep);
+        assert_eq!(
+            vec![("physiological bounds", 1), ("rate of change", 1)],
+            filtered.report.removed
+        );
+        assert_eq!(vec![1, 2, 4, 5], filtered.retain(&[0, 1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn test_pipeline_refuses_cadence_that_does_not_line_up() {
+        let samples = every_second(&[140; 4]);
+        let pipeline = FilterPipeline::from_kinds(&[FilterKind::CadenceLock]);
+
+        assert_eq!(
+            Err(HeartRateDriftError::LengthMismatch {
+                stream: "cadence",
+                expected: 4,
+                found: 3
+            }),
+            pipeline.run(&samples, Some(&[80.0; 3]))
+        );
+    }
+}
@@ -0,0 +1,466 @@
+use crate::analysis::Streams;
+use crate::heart_rate_drift::{BeatsPerMinute, HeartRateDriftError, Seconds};
+use crate::rr::beats_from_intervals;
+
+/// Bytes 8 to 11 of every FIT file
+const SIGNATURE: &[u8] = b".FIT";
+/// Global message number of the per-second records
+const RECORD: u16 = 20;
+/// Global message number of the beat-to-beat intervals, logged from the start of the activity
+const HRV: u16 = 78;
+/// The field of an HRV message holding its intervals, in milliseconds
+const HRV_TIME: u8 = 0;
+
+/// Field numbers in a record message
+const TIMESTAMP: u8 = 253;
+const ALTITUDE: u8 = 2;
+const HEART_RATE: u8 = 3;
+const CADENCE: u8 = 4;
+const DISTANCE: u8 = 5;
+const SPEED: u8 = 6;
+const POWER: u8 = 7;
+const TEMPERATURE: u8 = 13;
+const ENHANCED_SPEED: u8 = 73;
+const ENHANCED_ALTITUDE: u8 = 78;
+
+/// Whether `bytes` start like a FIT file rather than JSON
+pub fn is_fit(bytes: &[u8]) -> bool {
+    bytes.get(8..12) == Some(SIGNATURE)
+}
+
+/// Reads the records of a FIT activity into streams timed from the first record. Records
+/// without a heart rate are left out. Where the other records are missing a reading, like a
+/// temperature the watch only logs now and then, the reading before is carried forward so
+/// every stream lines up with the times. HRV messages, when the watch logged them, become the
+/// beats.
+pub fn read_fit(bytes: &[u8]) -> Result<Streams, HeartRateDriftError> {
+    let invalid = |reason| HeartRateDriftError::InvalidFitFile { reason };
+    if !is_fit(bytes) {
+        return Err(invalid("it has no FIT header"));
+    }
+    let header_size = usize::from(bytes[0]);
+    let data_size = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+    let data = bytes
+        .get(header_size..header_size.saturating_add(data_size))
+        .ok_or_else(|| invalid("it ends before the data its header gives"))?;
+
+    let mut definitions: [Option<Definition>; 16] = Default::default();
+    let mut last_timestamp: Option<u32> = None;
+    let mut records = Vec::new();
+    let mut intervals = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let header = data[position];
+        position += 1;
+        // A compressed timestamp header gives the time as an offset from the last timestamp,
+        // and only has room for local messages 0 to 3
+        let (local, time_offset) = if header & 0x80 != 0 {
+            (usize::from((header >> 5) & 0x03), Some(header & 0x1F))
+        } else {
+            (usize::from(header & 0x0F), None)
+        };
+        if time_offset.is_none() && header & 0x40 != 0 {
+            let (definition, length) = Definition::read(&data[position..], header & 0x20 != 0)
+                .ok_or_else(|| invalid("it ends partway through a message definition"))?;
+            definitions[local] = Some(definition);
+            position += length;
+            continue;
+        }
+
+        let definition = definitions[local]
+            .as_ref()
+            .ok_or_else(|| invalid("a message comes before its definition"))?;
+        let content = data
+            .get(position..position + definition.size)
+            .ok_or_else(|| invalid("it ends partway through a message"))?;
+        position += definition.size;
+        let timestamp = match time_offset {
+            Some(offset) => last_timestamp.map(|last| expand_timestamp(last, offset)),
+            None => definition
+                .value(content, TIMESTAMP)
+                .map(|timestamp| timestamp as u32),
+        };
+        if timestamp.is_some() {
+            last_timestamp = timestamp;
+        }
+        if definition.global == RECORD {
+            if let Some(timestamp) = timestamp {
+                records.push(Record::read(definition, content, timestamp));
+            }
+        }
+        if definition.global == HRV {
+            // Unused slots at the end of the array hold the invalid value
+            intervals.extend(definition.values(content, HRV_TIME).into_iter().flatten());
+        }
+    }
+
+    let start = records.first().map_or(0.0, |record| record.time);
+    records.retain(|record| record.heart_rate.is_some());
+    Ok(Streams {
+        heart_rates: records
+            .iter()
+            .filter_map(|record| record.heart_rate)
+            .map(|heart_rate| heart_rate as BeatsPerMinute)
+            .collect(),
+        times: records.iter().map(|record| record.time - start).collect(),
+        speeds: carried_forward(&records, |record| record.speed),
+        distances: carried_forward(&records, |record| record.distance),
+        watts: carried_forward(&records, |record| record.power),
+        cadence: carried_forward(&records, |record| record.cadence),
+        moving: None,
+        altitudes: carried_forward(&records, |record| record.altitude),
+        temperatures: carried_forward(&records, |record| record.temperature),
+        laps: None,
+        beats: (!intervals.is_empty()).then(|| beats_from_intervals(&intervals)),
+    })
+}
+
+/// A stream with a value for every record, when any record has one. Records before the first
+/// reading take that reading.
+fn carried_forward(
+    records: &[Record],
+    reading: impl Fn(&Record) -> Option<f64>,
+) -> Option<Vec<f64>> {
+    let mut last = records.iter().find_map(&reading)?;
+    Some(
+        records
+            .iter()
+            .map(|record| {
+                if let Some(value) = reading(record) {
+                    last = value;
+                }
+                last
+            })
+            .collect(),
+    )
+}
+
+/// The full timestamp for a compressed header's 5 bit offset, which rolls over every 32 seconds
+fn expand_timestamp(last: u32, offset: u8) -> u32 {
+    let offset = u32::from(offset);
+    let base = last & !0x1F;
+    if offset >= last & 0x1F {
+        base + offset
+    } else {
+        base + offset + 0x20
+    }
+}
+
+/// One record message, in the units the streams use
+struct Record {
+    time: Seconds,
+    heart_rate: Option<f64>,
+    /// Metres per second
+    speed: Option<f64>,
+    /// Metres
+    distance: Option<f64>,
+    /// Metres above sea level
+    altitude: Option<f64>,
+    cadence: Option<f64>,
+    power: Option<f64>,
+    /// °C
+    temperature: Option<f64>,
+}
+
+impl Record {
+    fn read(definition: &Definition, content: &[u8], timestamp: u32) -> Self {
+        let value = |number| definition.value(content, number);
+        Self {
+            time: f64::from(timestamp),
+            heart_rate: value(HEART_RATE),
+            speed: value(ENHANCED_SPEED)
+                .or_else(|| value(SPEED))
+                .map(|speed| speed / 1000.0),
+            distance: value(DISTANCE).map(|distance| distance / 100.0),
+            altitude: value(ENHANCED_ALTITUDE)
+                .or_else(|| value(ALTITUDE))
+                .map(|altitude| altitude / 5.0 - 500.0),
+            cadence: value(CADENCE),
+            power: value(POWER),
+            temperature: value(TEMPERATURE),
+        }
+    }
+}
+
+/// Where a field sits in the messages of one definition
+struct Field {
+    number: u8,
+    offset: usize,
+    size: usize,
+    base_type: u8,
+}
+
+/// The layout of the messages that follow with the same local message number
+struct Definition {
+    global: u16,
+    big_endian: bool,
+    fields: Vec<Field>,
+    /// Bytes in each message, including developer fields, which are skipped
+    size: usize,
+}
+
+impl Definition {
+    /// Reads a definition from the start of `bytes`, along with how many bytes it took
+    fn read(bytes: &[u8], has_developer_fields: bool) -> Option<(Self, usize)> {
+        let big_endian = *bytes.get(1)? == 1;
+        let global = [*bytes.get(2)?, *bytes.get(3)?];
+        let global = if big_endian {
+            u16::from_be_bytes(global)
+        } else {
+            u16::from_le_bytes(global)
+        };
+        let mut position = 5;
+        let mut size = 0;
+        let mut fields = Vec::new();
+        for _ in 0..*bytes.get(4)? {
+            let field = bytes.get(position..position + 3)?;
+            fields.push(Field {
+                number: field[0],
+                offset: size,
+                size: usize::from(field[1]),
+                base_type: field[2],
+            });
+            size += usize::from(field[1]);
+            position += 3;
+        }
+        if has_developer_fields {
+            let count = *bytes.get(position)?;
+            position += 1;
+            for _ in 0..count {
+                size += usize::from(*bytes.get(position + 1)?);
+                position += 3;
+            }
+        }
+        Some((
+            Self {
+                global,
+                big_endian,
+                fields,
+                size,
+            },
+            position,
+        ))
+    }
+
+    /// The field's value, or its first value when it's an array. None when the message
+    /// doesn't have the field or it holds FIT's invalid value.
+    fn value(&self, content: &[u8], number: u8) -> Option<f64> {
+        let field = self.field(number)?;
+        let bytes = content.get(field.offset..field.offset + field.size)?;
+        read_value(bytes, field.base_type, self.big_endian)
+    }
+
+    /// Every value of an array field, with None for FIT's invalid value
+    fn values(&self, content: &[u8], number: u8) -> Vec<Option<f64>> {
+        let Some(field) = self.field(number) else {
+            return Vec::new();
+        };
+        match (
+            content.get(field.offset..field.offset + field.size),
+            base_type_size(field.base_type),
+        ) {
+            (Some(bytes), Some(size)) => bytes
+                .chunks_exact(size)
+                .map(|value| read_value(value, field.base_type, self.big_endian))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn field(&self, number: u8) -> Option<&Field> {
+        self.fields.iter().find(|field| field.number == number)
+    }
+}
+
+/// Bytes in one value of a base type. None for strings.
+fn base_type_size(base_type: u8) -> Option<usize> {
+    match base_type & 0x1F {
+        0 | 1 | 2 | 10 | 13 => Some(1),
+        3 | 4 | 11 => Some(2),
+        5 | 6 | 8 | 12 => Some(4),
+        9 | 14 | 15 | 16 => Some(8),
+        _ => None,
+    }
+}
+
+/// The number at the start of `bytes`, or None when it's the value FIT marks missing with
+fn read_value(bytes: &[u8], base_type: u8, big_endian: bool) -> Option<f64> {
+    let bytes = bytes.get(..base_type_size(base_type)?)?;
+    let fold = |raw: u64, &byte: &u8| (raw << 8) | u64::from(byte);
+    let raw = if big_endian {
+        bytes.iter().fold(0, fold)
+    } else {
+        bytes.iter().rev().fold(0, fold)
+    };
+    match base_type & 0x1F {
+        0 | 2 | 13 => (raw != 0xFF).then_some(raw as f64),
+        1 => (raw != 0x7F).then_some(f64::from(raw as u8 as i8)),
+        3 => (raw != 0x7FFF).then_some(f64::from(raw as u16 as i16)),
+        4 => (raw != 0xFFFF).then_some(raw as f64),
+        5 => (raw != 0x7FFF_FFFF).then_some(f64::from(raw as u32 as i32)),
+        6 => (raw != 0xFFFF_FFFF).then_some(raw as f64),
+        8 => Some(f64::from(f32::from_bits(raw as u32))).filter(|value| value.is_finite()),
+        9 => Some(f64::from_bits(raw)).filter(|value| value.is_finite()),
+        10..=12 | 16 => (raw != 0).then_some(raw as f64),
+        14 => (raw != 0x7FFF_FFFF_FFFF_FFFF).then_some(raw as i64 as f64),
+        _ => (raw != u64::MAX).then_some(raw as f64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wraps messages in a FIT header and a CRC, which isn't checked
+    fn fit_file(messages: &[Vec<u8>]) -> Vec<u8> {
+        let data = messages.concat();
+        let mut file = vec![12, 0x10, 0, 0];
+        file.extend((data.len() as u32).to_le_bytes());
+        file.extend(SIGNATURE);
+        file.extend(data);
+        file.extend([0, 0]);
+        file
+    }
+
+    /// Local message 0 as a record with a timestamp, heart rate and temperature
+    fn record_definition() -> Vec<u8> {
+        vec![
+            0x40,
+            0,
+            0,
+            20,
+            0,
+            3,
+            TIMESTAMP,
+            4,
+            0x86,
+            HEART_RATE,
+            1,
+            0x02,
+            TEMPERATURE,
+            1,
+            0x01,
+        ]
+    }
+
+    fn record(timestamp: u32, heart_rate: u8, temperature: i8) -> Vec<u8> {
+        let mut message = vec![0x00];
+        message.extend(timestamp.to_le_bytes());
+        message.extend([heart_rate, temperature as u8]);
+        message
+    }
+
+    #[test]
+    fn test_records_are_timed_from_the_first() {
+        let file = fit_file(&[
+            record_definition(),
+            record(1000, 140, 20),
+            record(1001, 141, 21),
+            record(1003, 142, 22),
+        ]);
+
+        let streams = read_fit(&file).unwrap();
+
+        assert_eq!(vec![140, 141, 142], streams.heart_rates);
+        assert_eq!(vec![0.0, 1.0, 3.0], streams.times);
+        assert_eq!(Some(vec![20.0, 21.0, 22.0]), streams.temperatures);
+        assert_eq!(None, streams.speeds);
+    }
+
+    #[test]
+    fn test_missing_readings_are_left_out_or_carried_forward() {
+        let file = fit_file(&[
+            record_definition(),
+            record(1000, 0xFF, 20),
+            record(1001, 141, 0x7F),
+            record(1002, 142, 22),
+            record(1003, 143, 0x7F),
+        ]);
+
+        let streams = read_fit(&file).unwrap();
+
+        assert_eq!(vec![1.0, 2.0, 3.0], streams.times);
+        assert_eq!(Some(vec![22.0, 22.0, 22.0]), streams.temperatures);
+    }
+
+    #[test]
+    fn test_compressed_timestamps_count_on_from_the_last_one() {
+        // Local message 1 as a record with only a heart rate, as sent with compressed headers
+        let definition = vec![0x41, 0, 0, 20, 0, 1, HEART_RATE, 1, 0x02];
+        let compressed = |offset: u8, heart_rate| vec![0x80 | 0x20 | offset, heart_rate];
+        let file = fit_file(&[
+            record_definition(),
+            definition,
+            record(1000, 140, 20),
+            compressed(10, 141),
+            compressed(2, 142),
+        ]);
+
+        let streams = read_fit(&file).unwrap();
+
+        assert_eq!(vec![0.0, 2.0, 26.0], streams.times);
+    }
+
+    #[test]
+    fn test_big_endian_messages() {
+        let definition = vec![
+            0x40, 0, 1, 0, 20, 3, TIMESTAMP, 4, 0x86, HEART_RATE, 1, 0x02, SPEED, 2, 0x84,
+        ];
+        let mut message = vec![0x00];
+        message.extend(1000u32.to_be_bytes());
+        message.push(150);
+        message.extend(3250u16.to_be_bytes());
+
+        let streams = read_fit(&fit_file(&[definition, message])).unwrap();
+
+        assert_eq!(vec![150], streams.heart_rates);
+        assert_eq!(Some(vec![3.25]), streams.speeds);
+    }
+
+    #[test]
+    fn test_hrv_messages_are_the_beats() {
+        // Local message 1 as HRV with room for three intervals
+        let definition = vec![0x41, 0, 0, HRV as u8, 0, 1, HRV_TIME, 6, 0x84];
+        let hrv = |intervals: [u16; 3]| {
+            let mut message = vec![0x01];
+            message.extend(intervals.iter().flat_map(|interval| interval.to_le_bytes()));
+            message
+        };
+        let file = fit_file(&[
+            record_definition(),
+            definition,
+            record(1000, 140, 20),
+            hrv([500, 520, 480]),
+            hrv([510, 0xFFFF, 0xFFFF]),
+        ]);
+
+        let beats = read_fit(&file).unwrap().beats.unwrap();
+
+        assert_eq!(
+            vec![500.0, 520.0, 480.0, 510.0],
+            beats.iter().map(|beat| beat.interval).collect::<Vec<_>>()
+        );
+        assert_eq!(2.01, beats[3].time);
+    }
+
+    #[test]
+    fn test_json_is_not_a_fit_file() {
+        assert_eq!(
+            Err(HeartRateDriftError::InvalidFitFile {
+                reason: "it has no FIT header"
+            }),
+            read_fit(b"{\"heartrate\": {\"data\": []}}")
+        );
+    }
+
+    #[test]
+    fn test_truncated_file_is_an_error() {
+        let mut file = fit_file(&[record_definition(), record(1000, 140, 20)]);
+        file.truncate(20);
+
+        assert!(matches!(
+            read_fit(&file),
+            Err(HeartRateDriftError::InvalidFitFile { .. })
+        ));
+    }
+}
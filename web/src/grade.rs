@@ -0,0 +1,199 @@
+use crate::heart_rate_drift::{HeartRateDriftError, Seconds};
+use serde::Deserialize;
+
+const SECONDS_PER_HOUR: f64 = 3600.0;
+
+/// Grades steeper than this are off the end of the curve Minetti measured
+const MAX_GRADE: f64 = 0.45;
+
+/// Whether to adjust pace for the hills, and how far the grade is measured over
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GradeOptions {
+    /// In pace mode, also calculate decoupling from grade-adjusted pace. Needs the altitude and
+    /// distance streams
+    #[arg(long)]
+    pub grade_adjusted: bool,
+    /// Metres of distance the grade is measured over. GPS altitude is noisy, so shorter than
+    /// this gives spiky grades
+    #[arg(long, default_value_t = GradeOptions::default().grade_smoothing)]
+    pub grade_smoothing: f64,
+}
+
+impl Default for GradeOptions {
+    fn default() -> Self {
+        Self {
+            grade_adjusted: false,
+            grade_smoothing: 100.0,
+        }
+    }
+}
+
+/// Energy cost of running at `grade` (rise over run), in J/kg/m, from Minetti et al. (2002),
+/// "Energy cost of walking and running at extreme uphill and downhill slopes"
+pub fn energy_cost(grade: f64) -> f64 {
+    let i = grade.clamp(-MAX_GRADE, MAX_GRADE);
+    155.4 * i.powi(5) - 30.4 * i.powi(4) - 43.3 * i.powi(3) + 46.3 * i.powi(2) + 19.5 * i + 3.6
+}
+
+/// Grade at each sample, measured between the samples `smoothing` metres of distance apart
+/// centred on it. `distances` is cumulative, so it never goes down. There has to be an altitude
+/// for every distance.
+pub fn grades(
+    altitudes: &[f64],
+    distances: &[f64],
+    smoothing: f64,
+) -> Result<Vec<f64>, HeartRateDriftError> {
+    if altitudes.len() != distances.len() {
+        return Err(HeartRateDriftError::LengthMismatch {
+            stream: "altitude",
+            expected: distances.len(),
+            found: altitudes.len(),
+        });
+    }
+    let half = smoothing / 2.0;
+    Ok(distances
+        .iter()
+        .map(|&distance| {
+            let from = distances
+                .partition_point(|&other| other < distance - half)
+                .min(distances.len() - 1);
+            let to = distances
+                .partition_point(|&other| other <= distance + half)
+                .saturating_sub(1);
+            let run = distances[to] - distances[from];
+            if run < 1.0 {
+                0.0
+            } else {
+                (altitudes[to] - altitudes[from]) / run
+            }
+        })
+        .collect())
+}
+
+/// The speed on the flat that would take the same effort as each of `speeds` at its grade
+pub fn grade_adjusted_speeds(speeds: &[f64], grades: &[f64]) -> Vec<f64> {
+    let flat = energy_cost(0.0);
+    speeds
+        .iter()
+        .zip(grades)
+        .map(|(speed, &grade)| speed * energy_cost(grade) / flat)
+        .collect()
+}
+
+/// Metres climbed per hour at each sample, measured between the samples `smoothing` seconds
+/// apart centred on it. Descending does no climbing work, so descents count as zero rather
+/// than cancelling out the climbing either side of them. There has to be an altitude for every
+/// time.
+pub fn ascent_rates(
+    altitudes: &[f64],
+    times: &[Seconds],
+    smoothing: i32,
+) -> Result<Vec<f64>, HeartRateDriftError> {
+    if altitudes.len() != times.len() {
+        return Err(HeartRateDriftError::LengthMismatch {
+            stream: "altitude",
+            expected: times.len(),
+            found: altitudes.len(),
+        });
+    }
+    let half = f64::from(smoothing) / 2.0;
+    Ok(times
+        .iter()
+        .map(|&time| {
+            let from = times
+                .partition_point(|&other| other < time - half)
+                .min(times.len() - 1);
+            let to = times
+                .partition_point(|&other| other <= time + half)
+                .saturating_sub(1);
+            let elapsed = times[to] - times[from];
+            if elapsed <= 0.0 {
+                0.0
+            } else {
+                let climbed = altitudes[to] - altitudes[from];
+                (climbed / elapsed * SECONDS_PER_HOUR).max(0.0)
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flat_ground_needs_no_adjustment() {
+        let distances: Vec<f64> = (0..10).map(|metres| f64::from(metres) * 50.0).collect();
+        let grades = grades(&[10.0; 10], &distances, 100.0).unwrap();
+
+        assert_eq!(
+            vec![3.0, 3.5],
+            grade_adjusted_speeds(&[3.0, 3.5], &grades[..2])
+        );
+    }
+
+    #[test]
+    fn test_grade_is_measured_over_the_smoothing_distance() {
+        let distances: Vec<f64> = (0..11).map(|metres| f64::from(metres) * 25.0).collect();
+        let altitudes: Vec<f64> = distances.iter().map(|distance| distance * 0.1).collect();
+
+        let grades = grades(&altitudes, &distances, 100.0).unwrap();
+
+        assert!(grades.iter().all(|grade| (grade - 0.1).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_uphill_is_worth_more_than_flat_and_gentle_downhill_less() {
+        let adjusted = grade_adjusted_speeds(&[3.0, 3.0, 3.0], &[0.1, 0.0, -0.05]);
+
+        assert!(adjusted[0] > 3.0);
+        assert_eq!(3.0, adjusted[1]);
+        assert!(adjusted[2] < 3.0);
+    }
+
+    #[test]
+    fn test_ascent_rate_in_metres_per_hour() {
+        let times: Vec<Seconds> = (0..=120).map(f64::from).collect();
+        let altitudes: Vec<f64> = times.iter().map(|&time| time * 0.25).collect();
+
+        let rates = ascent_rates(&altitudes, &times, 60).unwrap();
+
+        assert!(rates.iter().all(|&rate| rate == 900.0));
+    }
+
+    #[test]
+    fn test_descending_is_no_ascent() {
+        let times = [0.0, 30.0, 60.0];
+
+        assert_eq!(
+            Ok(vec![0.0, 0.0, 0.0]),
+            ascent_rates(&[1000.0, 990.0, 980.0], &times, 60)
+        );
+    }
+
+    #[test]
+    fn test_standing_still_has_no_grade() {
+        assert_eq!(
+            Ok(vec![0.0, 0.0]),
+            grades(&[10.0, 12.0], &[5.0, 5.0], 100.0)
+        );
+    }
+
+    #[test]
+    fn test_grade_needs_an_altitude_for_every_distance() {
+        assert_eq!(
+            Err(HeartRateDriftError::LengthMismatch {
+                stream: "altitude",
+                expected: 3,
+                found: 2
+            }),
+            grades(&[10.0, 12.0], &[0.0, 5.0, 10.0], 100.0)
+        );
+    }
+
+    #[test]
+    fn test_ascent_rate_needs_an_altitude_for_every_time() {
+        assert!(ascent_rates(&[1000.0], &[0.0, 30.0], 60).is_err());
+    }
+}
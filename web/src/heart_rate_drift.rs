@@ -1,55 +1,410 @@
+use crate::averaging::{Averaging, RunningAverage};
+use crate::bootstrap::{drift_interval, BootstrapOptions};
+use crate::interpretation::Interpretation;
+use crate::protocol::{DriftProtocol, Segment};
+use crate::quality::{DataQuality, QualityCheck, QualityThresholds};
+use crate::report::{DriftReport, SegmentSummary, Verdict};
+use crate::trend::{DriftTrend, Fit, TrendFit};
+use crate::validation::{validate_streams, Repair};
+use serde::{Serialize, Serializer};
 use thiserror::Error;
 
-const WARM_UP_LIMIT: i32 = 900;
-const FIRST_SEGMENT_LIMIT: i32 = 2700;
-const LAST_SEGMENT_LIMIT: i32 = 4500;
-
 #[derive(PartialEq, Error, Debug)]
 pub enum HeartRateDriftError {
-    #[error("Not enough samples to calculate drift. For the calculation you need a 15 min warm-up, and two 30 min segments, so at least three samples")]
+    #[error("Not enough samples to calculate drift. The first and last segments of the test each need at least one sample")]
     NotEnoughSamples,
+    #[error("The test protocol needs at least two segments of positive length")]
+    InvalidProtocol,
+    #[error("There is no pace or power recorded in the first segment to compare against")]
+    NoOutput,
+    #[error("Only {coverage:.1}% of segment {segment} has heart rate samples, which isn't enough to trust the drift")]
+    InsufficientCoverage { segment: usize, coverage: f64 },
+    #[error(
+        "The test needs {needed} laps including the warm-up, but the activity only has {found}"
+    )]
+    NotEnoughLaps { needed: usize, found: usize },
+    #[error("Lap {lap} is only {} long, which is too short to be a test segment", crate::report::minutes_and_seconds(*length))]
+    LapTooShort { lap: usize, length: i32 },
+    #[error("The laps set where the test is, so the {option} option can't be used with them")]
+    OptionIgnoredByLaps { option: &'static str },
+    #[error("The {stream} stream has {found} samples but there are {expected} times")]
+    LengthMismatch {
+        stream: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    #[error("Sample {index} is at {time}s, but times can't be negative")]
+    NegativeTime { index: usize, time: Seconds },
+    #[error("Sample {index} is at {time}s, which isn't a time")]
+    InvalidTime { index: usize, time: Seconds },
+    #[error("Time goes backwards to {time}s at sample {index}. Repair with sort to put the samples back in order")]
+    UnsortedTimes { index: usize, time: Seconds },
+    #[error("There is more than one sample at {time}s. Repair with dedupe to keep the first")]
+    DuplicateTime { time: Seconds },
+    #[error("The protocol splits the whole activity, so it needs to know how long the activity is up front")]
+    UnknownDuration,
+    #[error("The sample at {time}s came after one at {latest}s. Live samples can't be put back in order, so it was left out")]
+    LateSample { time: Seconds, latest: Seconds },
+    #[error("Line {line} of the RR file isn't a valid interval")]
+    InvalidRrInterval { line: usize },
+    #[error("This FIT file can't be read, because {reason}")]
+    InvalidFitFile { reason: &'static str },
+    #[error("{needed_by} needs the {stream} stream")]
+    MissingStream {
+        stream: &'static str,
+        needed_by: &'static str,
+    },
+    #[error("Grade-adjusted pace only works in pace mode")]
+    GradeAdjustedNeedsPace,
+    #[error("Couldn't find the test in the activity. The protocol needs a fixed segment length, and the activity has to be at least as long as the test")]
+    TestNotFound,
+}
+
+/// Heart rate as recorded, in beats per minute
+pub type BeatsPerMinute = i32;
+/// Seconds since the start of the activity. Fractional, for FIT files and beat-to-beat data
+/// that are timed more finely than a second, and wide enough for multi-day activities, which
+/// overflow an `i16` after about 9 hours.
+pub type Seconds = f64;
+
+/// Serializes whole seconds as integers, so the JSON for whole-second data reads the same as it
+/// did before times could be fractional. For `#[serde(serialize_with = "whole_seconds")]`.
+pub(crate) fn whole_seconds<S: Serializer>(
+    seconds: &Seconds,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if seconds.fract() == 0.0 && seconds.abs() < i64::MAX as f64 {
+        serializer.serialize_i64(*seconds as i64)
+    } else {
+        serializer.serialize_f64(*seconds)
+    }
+}
+
+/// `whole_seconds` for every time in a list
+pub(crate) fn all_whole_seconds<S: Serializer>(
+    times: &[Seconds],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(times.iter().map(|&time| WholeSeconds(time)))
 }
 
-#[derive(PartialEq, Debug)]
+struct WholeSeconds(Seconds);
+
+impl Serialize for WholeSeconds {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        whole_seconds(&self.0, serializer)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub struct HeartRateAtTime {
-    heart_rate: i32,
-    time: i32,
+    pub heart_rate: BeatsPerMinute,
+    pub time: Seconds,
+}
+
+/// Everything that shapes how drift is calculated
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DriftSettings {
+    pub protocol: DriftProtocol,
+    pub averaging: Averaging,
+    pub quality: QualityThresholds,
+    pub fit: Fit,
+    pub bootstrap: BootstrapOptions,
+}
+
+impl From<DriftProtocol> for DriftSettings {
+    fn from(protocol: DriftProtocol) -> Self {
+        Self {
+            protocol,
+            ..Default::default()
+        }
+    }
 }
 
 pub trait HeartRateDrift {
-    fn heart_rate_drift(&self) -> Result<f64, HeartRateDriftError>;
+    fn heart_rate_drift(
+        &self,
+        settings: &DriftSettings,
+    ) -> Result<DriftReport, HeartRateDriftError>;
+}
+
+impl HeartRateDrift for [HeartRateAtTime] {
+    /// Samples can come in any order. They're only copied to be sorted when they aren't in time
+    /// order already.
+    fn heart_rate_drift(
+        &self,
+        settings: &DriftSettings,
+    ) -> Result<DriftReport, HeartRateDriftError> {
+        if self.is_sorted_by(|earlier, later| earlier.time <= later.time) {
+            return drift_of_sorted(self, settings);
+        }
+        let mut sorted = self.to_vec();
+        sorted.sort_by(|earlier, later| earlier.time.total_cmp(&later.time));
+        drift_of_sorted(&sorted, settings)
+    }
 }
 
 impl HeartRateDrift for Vec<HeartRateAtTime> {
-    fn heart_rate_drift(&self) -> Result<f64, HeartRateDriftError> {
-        let first_segment: Vec<i32> = self
-            .iter()
-            .filter(|sample| sample.time >= WARM_UP_LIMIT && sample.time < FIRST_SEGMENT_LIMIT)
-            .map(|sample| sample.heart_rate)
-            .collect();
+    fn heart_rate_drift(
+        &self,
+        settings: &DriftSettings,
+    ) -> Result<DriftReport, HeartRateDriftError> {
+        self.as_slice().heart_rate_drift(settings)
+    }
+}
+
+fn drift_of_sorted(
+    samples: &[HeartRateAtTime],
+    settings: &DriftSettings,
+) -> Result<DriftReport, HeartRateDriftError> {
+    // Invalid times sort to the end, and are refused once they're pushed
+    let last_time = samples
+        .iter()
+        .rev()
+        .map(|sample| sample.time)
+        .find(|time| time.is_finite())
+        .unwrap_or_default();
+    let mut accumulator = DriftAccumulator::new(settings, Some(last_time))?;
+    accumulator.push_all(samples.iter().copied())?;
+    accumulator.finish()
+}
+
+/// Calculates drift in a single pass over the samples, without holding on to them, so an
+/// activity can be streamed from a file or a sensor, or taken from any iterator. Samples have
+/// to arrive in time order. A protocol that splits the whole activity needs to know when it
+/// ends up front.
+///
+/// Only the bootstrap and the Theil-Sen trend need to look at samples more than once, so those
+/// keep the samples they use.
+#[derive(Clone, Debug)]
+pub struct DriftAccumulator {
+    settings: DriftSettings,
+    windows: Vec<WindowAccumulator>,
+    /// The window the last sample was in or before
+    current: usize,
+    pushed: usize,
+    latest: Option<Seconds>,
+    /// From the start of the first compared segment to the end of the last, which the trend
+    /// is fitted over
+    tested: Segment,
+    trend: TrendFit,
+}
 
-        let second_segment: Vec<i32> = self
+/// What is kept about the samples in one window of the protocol
+#[derive(Clone, Debug)]
+struct WindowAccumulator {
+    window: Segment,
+    count: usize,
+    average: RunningAverage,
+    min: Option<f64>,
+    max: Option<f64>,
+    quality: QualityCheck,
+    /// Kept for the bootstrap, in the compared windows only
+    samples: Option<Vec<(Seconds, f64)>>,
+}
+
+impl DriftAccumulator {
+    /// Lays the protocol over an activity whose last sample is at `last_time`. Without it, the
+    /// windows are laid out to the protocol's planned length, which a split protocol doesn't
+    /// have.
+    pub fn new(
+        settings: &DriftSettings,
+        last_time: Option<Seconds>,
+    ) -> Result<Self, HeartRateDriftError> {
+        let last_time = match last_time {
+            Some(last_time) => last_time,
+            // One second before the end, where the last sample of 1 Hz data falls
+            None => settings
+                .protocol
+                .planned_length()
+                .map(|length| f64::from(length - 1))
+                .ok_or(HeartRateDriftError::UnknownDuration)?,
+        };
+        let (first, last) = compared_windows(&settings.protocol, last_time)?;
+        let windows = settings.protocol.windows(last_time);
+        let bootstrapping = settings
+            .bootstrap
+            .bootstrap
+            .is_some_and(|resamples| resamples > 0);
+        let compared = [0, windows.len() - 1];
+        Ok(Self {
+            windows: windows
+                .iter()
+                .enumerate()
+                .map(|(index, window)| WindowAccumulator {
+                    window: *window,
+                    count: 0,
+                    average: RunningAverage::new(settings.averaging),
+                    min: None,
+                    max: None,
+                    quality: QualityCheck::new(window, &settings.quality),
+                    samples: (bootstrapping && compared.contains(&index)).then(Vec::new),
+                })
+                .collect(),
+            current: 0,
+            pushed: 0,
+            latest: None,
+            tested: Segment {
+                start: first.start,
+                end: last.end,
+            },
+            trend: TrendFit::new(settings.fit),
+            settings: settings.clone(),
+        })
+    }
+
+    /// Adds the next sample. The windows are only walked forwards, so a sample earlier than the
+    /// one before it is refused rather than left out of its window.
+    pub fn push(&mut self, sample: HeartRateAtTime) -> Result<(), HeartRateDriftError> {
+        let (time, rate) = (sample.time, f64::from(sample.heart_rate));
+        let index = self.pushed;
+        self.pushed += 1;
+        if !time.is_finite() {
+            return Err(HeartRateDriftError::InvalidTime { index, time });
+        }
+        if time < 0.0 {
+            return Err(HeartRateDriftError::NegativeTime { index, time });
+        }
+        if self.latest.is_some_and(|latest| time < latest) {
+            return Err(HeartRateDriftError::UnsortedTimes { index, time });
+        }
+        self.latest = Some(time);
+        if self.tested.contains(time) {
+            self.trend.push(time, rate);
+        }
+        while self
+            .windows
+            .get(self.current)
+            .is_some_and(|window| time >= f64::from(window.window.end))
+        {
+            self.current += 1;
+        }
+        let Some(window) = self.windows.get_mut(self.current) else {
+            return Ok(());
+        };
+        if !window.window.contains(time) {
+            return Ok(());
+        }
+        window.count += 1;
+        window.average.push(time, rate);
+        window.min = Some(window.min.map_or(rate, |min| min.min(rate)));
+        window.max = Some(window.max.map_or(rate, |max| max.max(rate)));
+        window.quality.push(time, rate);
+        if let Some(samples) = &mut window.samples {
+            samples.push((time, rate));
+        }
+        Ok(())
+    }
+
+    /// Adds samples in time order, stopping at the first that goes back in time
+    pub fn push_all<I: IntoIterator<Item = HeartRateAtTime>>(
+        &mut self,
+        samples: I,
+    ) -> Result<(), HeartRateDriftError> {
+        samples.into_iter().try_for_each(|sample| self.push(sample))
+    }
+
+    /// The segments as they stand, averaged over the samples so far
+    pub fn segments(&self) -> Vec<SegmentSummary> {
+        self.windows
             .iter()
-            .filter(|sample| sample.time >= FIRST_SEGMENT_LIMIT && sample.time < LAST_SEGMENT_LIMIT)
-            .map(|sample| sample.heart_rate)
-            .collect();
+            .map(|window| SegmentSummary {
+                start: window.window.start,
+                end: window.window.end,
+                samples: window.count,
+                average_heart_rate: window.average.average(),
+                min_heart_rate: window.min,
+                max_heart_rate: window.max,
+            })
+            .collect()
+    }
+
+    /// The line fitted to the samples so far in the compared part of the test
+    pub fn trend(&self) -> Option<DriftTrend> {
+        let (first, last) = (
+            self.windows[0].window,
+            self.windows[self.windows.len() - 1].window,
+        );
+        let middle = |window: Segment| f64::from(window.start + window.end) / 2.0;
+        self.trend.finish(first.start, middle(first), middle(last))
+    }
 
-        if first_segment.is_empty() || second_segment.is_empty() {
-            Err(HeartRateDriftError::NotEnoughSamples)
-        } else {
-            let first_heart_rate_total: f64 = first_segment.iter().sum::<i32>().into();
-            let second_heart_rate_total: f64 = second_segment.iter().sum::<i32>().into();
-            let avg_heart_rate_first = first_heart_rate_total / first_segment.len() as f64;
-            let avg_heart_rate_second = second_heart_rate_total / second_segment.len() as f64;
-
-            let drift =
-                ((avg_heart_rate_second - avg_heart_rate_first) / avg_heart_rate_first) * 100.0;
-            Ok(drift)
+    pub fn finish(self) -> Result<DriftReport, HeartRateDriftError> {
+        let segments = self.segments();
+        let trend = self.trend();
+        let settings = self.settings;
+        let mut quality = DataQuality::default();
+        let mut compared_samples = Vec::new();
+        for window in self.windows {
+            quality.segments.push(window.quality.finish());
+            compared_samples.extend(window.samples);
         }
+
+        if let Some(min_coverage) = settings.quality.min_coverage {
+            let compared = [0, quality.segments.len() - 1];
+            if let Some(&index) = compared
+                .iter()
+                .find(|&&index| quality.segments[index].coverage < min_coverage)
+            {
+                return Err(HeartRateDriftError::InsufficientCoverage {
+                    segment: index + 1,
+                    coverage: quality.segments[index].coverage,
+                });
+            }
+        }
+
+        let confidence = match compared_samples.as_slice() {
+            [first, last] => drift_interval(first, last, settings.averaging, &settings.bootstrap),
+            _ => None,
+        };
+
+        match (
+            segments[0].average_heart_rate,
+            segments[segments.len() - 1].average_heart_rate,
+        ) {
+            (Some(avg_heart_rate_first), Some(avg_heart_rate_second)) => {
+                let drift =
+                    ((avg_heart_rate_second - avg_heart_rate_first) / avg_heart_rate_first) * 100.0;
+                Ok(DriftReport {
+                    protocol: settings.protocol,
+                    detected_window: None,
+                    stops: None,
+                    segments,
+                    drift,
+                    verdict: Verdict::from_drift(drift),
+                    confidence,
+                    trend,
+                    quality,
+                    decoupling: None,
+                    grade_adjusted_decoupling: None,
+                    cardiac_lag: None,
+                    temperature: None,
+                    interpretation: Interpretation::new(drift, avg_heart_rate_first, None),
+                    dfa_alpha1: None,
+                    hrv: None,
+                })
+            }
+            _ => Err(HeartRateDriftError::NotEnoughSamples),
+        }
+    }
+}
+
+/// The first and last windows of the protocol, which are the two that drift compares
+pub(crate) fn compared_windows(
+    protocol: &DriftProtocol,
+    last_time: Seconds,
+) -> Result<(Segment, Segment), HeartRateDriftError> {
+    if !protocol.is_valid() {
+        return Err(HeartRateDriftError::InvalidProtocol);
     }
+    let windows = protocol.windows(last_time);
+    Ok((windows[0], windows[windows.len() - 1]))
 }
 
-pub fn combine_hr_with_time(heart_rates: &[i32], times: &[i32]) -> Vec<HeartRateAtTime> {
+pub fn combine_hr_with_time(
+    heart_rates: &[BeatsPerMinute],
+    times: &[Seconds],
+) -> Vec<HeartRateAtTime> {
     heart_rates
         .iter()
         .copied()
@@ -61,40 +416,96 @@ pub fn combine_hr_with_time(heart_rates: &[i32], times: &[i32]) -> Vec<HeartRate
         .collect()
 }
 
+/// Like `combine_hr_with_time`, but checks the streams first and applies the allowed `repairs`
+pub fn try_combine_hr_with_time(
+    heart_rates: &[BeatsPerMinute],
+    times: &[Seconds],
+    repairs: &[Repair],
+) -> Result<Vec<HeartRateAtTime>, HeartRateDriftError> {
+    let order = validate_streams(times, &[("heartrate", heart_rates.len())], repairs)?;
+    Ok(combine_hr_with_time(
+        &order.apply(heart_rates),
+        &order.apply(times),
+    ))
+}
+
+/// Samples a second apart from 0s, for tests
+#[cfg(test)]
+pub(crate) fn every_second(heart_rates: &[BeatsPerMinute]) -> Vec<HeartRateAtTime> {
+    let times: Vec<Seconds> = (0..heart_rates.len() as i32).map(f64::from).collect();
+    combine_hr_with_time(heart_rates, &times)
+}
+
+/// `(time, value)` pairs a second apart from `from` up to `to`, for tests
+#[cfg(test)]
+pub(crate) fn values_every_second(
+    from: i32,
+    to: i32,
+    value: impl Fn(i32) -> f64,
+) -> Vec<(Seconds, f64)> {
+    (from..to)
+        .map(|time| (f64::from(time), value(time)))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::SegmentLength;
+
+    const WARM_UP_LIMIT: Seconds = 900.0;
+    const FIRST_SEGMENT_LIMIT: Seconds = 2700.0;
+    const LAST_SEGMENT_LIMIT: Seconds = 4500.0;
 
     #[test]
     fn test_couple_heart_rates_and_times() {
         let heart_rates = vec![0, 1, 2];
-        let times = vec![3, 4, 5];
+        let times = vec![3.0, 4.0, 5.0];
         let actual_vec = combine_hr_with_time(&heart_rates, &times);
 
         let expected_vec = vec![
             HeartRateAtTime {
                 heart_rate: 0,
-                time: 3,
+                time: 3.0,
             },
             HeartRateAtTime {
                 heart_rate: 1,
-                time: 4,
+                time: 4.0,
             },
             HeartRateAtTime {
                 heart_rate: 2,
-                time: 5,
+                time: 5.0,
             },
         ];
         assert_eq!(expected_vec, actual_vec);
     }
 
+    #[test]
+    fn test_try_combine_repairs_what_it_is_allowed_to() {
+        let heart_rates = [100, 102, 101, 101];
+        let times = [0.0, 2.0, 1.0, 1.0];
+
+        assert_eq!(
+            Err(HeartRateDriftError::UnsortedTimes {
+                index: 2,
+                time: 1.0
+            }),
+            try_combine_hr_with_time(&heart_rates, &times, &[Repair::Dedupe])
+        );
+        assert_eq!(
+            combine_hr_with_time(&[100, 101, 102], &[0.0, 1.0, 2.0]),
+            try_combine_hr_with_time(&heart_rates, &times, &[Repair::Sort, Repair::Dedupe])
+                .unwrap()
+        );
+    }
+
     #[test]
     fn test_error_not_enough_samples_for_heart_rate_drift() {
         let samples = vec![];
 
         assert_eq!(
             Err(HeartRateDriftError::NotEnoughSamples),
-            samples.heart_rate_drift()
+            samples.heart_rate_drift(&DriftSettings::default())
         );
     }
 
@@ -102,12 +513,12 @@ mod tests {
     fn test_one_sample_is_not_enough_samples_for_heart_rate_drift() {
         let samples = vec![HeartRateAtTime {
             heart_rate: 0,
-            time: 1,
+            time: 1.0,
         }];
 
         assert_eq!(
             Err(HeartRateDriftError::NotEnoughSamples),
-            samples.heart_rate_drift()
+            samples.heart_rate_drift(&DriftSettings::default())
         );
     }
 
@@ -116,19 +527,24 @@ mod tests {
         let samples = vec![
             HeartRateAtTime {
                 heart_rate: 1,
-                time: WARM_UP_LIMIT - 1,
+                time: WARM_UP_LIMIT - 1.0,
             },
             HeartRateAtTime {
                 heart_rate: 1,
-                time: FIRST_SEGMENT_LIMIT - 1,
+                time: FIRST_SEGMENT_LIMIT - 1.0,
             },
             HeartRateAtTime {
                 heart_rate: 1,
-                time: LAST_SEGMENT_LIMIT - 1,
+                time: LAST_SEGMENT_LIMIT - 1.0,
             },
         ];
 
-        assert_eq!(Ok(0.0), samples.heart_rate_drift());
+        assert_eq!(
+            Ok(0.0),
+            samples
+                .heart_rate_drift(&DriftSettings::default())
+                .map(|result| result.drift)
+        );
     }
 
     #[test]
@@ -136,21 +552,21 @@ mod tests {
         let samples = vec![
             HeartRateAtTime {
                 heart_rate: 1,
-                time: WARM_UP_LIMIT - 1,
+                time: WARM_UP_LIMIT - 1.0,
             },
             HeartRateAtTime {
                 heart_rate: 1,
-                time: FIRST_SEGMENT_LIMIT - 2,
+                time: FIRST_SEGMENT_LIMIT - 2.0,
             },
             HeartRateAtTime {
                 heart_rate: 1,
-                time: FIRST_SEGMENT_LIMIT - 1,
+                time: FIRST_SEGMENT_LIMIT - 1.0,
             },
         ];
 
         assert_eq!(
             Err(HeartRateDriftError::NotEnoughSamples),
-            samples.heart_rate_drift()
+            samples.heart_rate_drift(&DriftSettings::default())
         );
     }
 
@@ -159,21 +575,21 @@ mod tests {
         let samples = vec![
             HeartRateAtTime {
                 heart_rate: 1,
-                time: WARM_UP_LIMIT - 1,
+                time: WARM_UP_LIMIT - 1.0,
             },
             HeartRateAtTime {
                 heart_rate: 1,
-                time: FIRST_SEGMENT_LIMIT + 1,
+                time: FIRST_SEGMENT_LIMIT + 1.0,
             },
             HeartRateAtTime {
                 heart_rate: 1,
-                time: FIRST_SEGMENT_LIMIT + 2,
+                time: FIRST_SEGMENT_LIMIT + 2.0,
             },
         ];
 
         assert_eq!(
             Err(HeartRateDriftError::NotEnoughSamples),
-            samples.heart_rate_drift()
+            samples.heart_rate_drift(&DriftSettings::default())
         );
     }
 
@@ -182,15 +598,20 @@ mod tests {
         let samples = vec![
             HeartRateAtTime {
                 heart_rate: 1,
-                time: WARM_UP_LIMIT + 1,
+                time: WARM_UP_LIMIT + 1.0,
             },
             HeartRateAtTime {
                 heart_rate: 1,
-                time: FIRST_SEGMENT_LIMIT + 1,
+                time: FIRST_SEGMENT_LIMIT + 1.0,
             },
         ];
 
-        assert_eq!(Ok(0.0), samples.heart_rate_drift());
+        assert_eq!(
+            Ok(0.0),
+            samples
+                .heart_rate_drift(&DriftSettings::default())
+                .map(|result| result.drift)
+        );
     }
 
     #[test]
@@ -198,14 +619,427 @@ mod tests {
         let samples = vec![
             HeartRateAtTime {
                 heart_rate: 1,
-                time: WARM_UP_LIMIT + 1,
+                time: WARM_UP_LIMIT + 1.0,
             },
             HeartRateAtTime {
                 heart_rate: 2,
-                time: FIRST_SEGMENT_LIMIT + 1,
+                time: FIRST_SEGMENT_LIMIT + 1.0,
+            },
+        ];
+
+        assert_eq!(
+            Ok(100.0),
+            samples
+                .heart_rate_drift(&DriftSettings::default())
+                .map(|result| result.drift)
+        );
+    }
+
+    #[test]
+    fn test_heart_rate_drift_uses_the_protocol_windows() {
+        let protocol = DriftProtocol {
+            start: 0,
+            warm_up: 1200,
+            segment_length: SegmentLength::Fixed(2700),
+            segments: 2,
+            cool_down: 0,
+        };
+        let samples = vec![
+            HeartRateAtTime {
+                heart_rate: 1,
+                time: WARM_UP_LIMIT + 1.0,
+            },
+            HeartRateAtTime {
+                heart_rate: 2,
+                time: 1200.0,
+            },
+            HeartRateAtTime {
+                heart_rate: 3,
+                time: 3900.0,
+            },
+        ];
+
+        assert_eq!(
+            Ok(50.0),
+            samples
+                .heart_rate_drift(&protocol.into())
+                .map(|result| result.drift)
+        );
+    }
+
+    #[test]
+    fn test_drift_compares_first_and_last_of_several_segments() {
+        let protocol = DriftProtocol {
+            segments: 3,
+            ..DriftProtocol::uphill_athlete()
+        };
+        let samples = vec![
+            HeartRateAtTime {
+                heart_rate: 100,
+                time: WARM_UP_LIMIT,
+            },
+            HeartRateAtTime {
+                heart_rate: 200,
+                time: FIRST_SEGMENT_LIMIT,
+            },
+            HeartRateAtTime {
+                heart_rate: 125,
+                time: LAST_SEGMENT_LIMIT,
+            },
+        ];
+
+        assert_eq!(
+            Ok(25.0),
+            samples
+                .heart_rate_drift(&protocol.into())
+                .map(|result| result.drift)
+        );
+    }
+
+    #[test]
+    fn test_test_at_the_end_of_a_multi_day_activity() {
+        const TWO_DAYS: i32 = 48 * 60 * 60;
+        let protocol = DriftProtocol {
+            start: TWO_DAYS,
+            ..DriftProtocol::uphill_athlete()
+        };
+        let samples = vec![
+            HeartRateAtTime {
+                heart_rate: 100,
+                time: f64::from(TWO_DAYS) + WARM_UP_LIMIT,
+            },
+            HeartRateAtTime {
+                heart_rate: 110,
+                time: f64::from(TWO_DAYS) + FIRST_SEGMENT_LIMIT,
             },
         ];
 
-        assert_eq!(Ok(100.0), samples.heart_rate_drift());
+        assert_eq!(
+            Ok(10.0),
+            samples
+                .heart_rate_drift(&protocol.into())
+                .map(|result| result.drift)
+        );
+    }
+
+    #[test]
+    fn test_sub_second_samples_fall_in_the_right_window() {
+        let samples = combine_hr_with_time(
+            &[200, 100, 100, 110, 110],
+            &[
+                WARM_UP_LIMIT - 0.25,
+                WARM_UP_LIMIT,
+                WARM_UP_LIMIT + 0.5,
+                FIRST_SEGMENT_LIMIT + 0.5,
+                LAST_SEGMENT_LIMIT - 0.5,
+            ],
+        );
+
+        let report = samples.heart_rate_drift(&DriftSettings::default()).unwrap();
+
+        assert_eq!(2, report.segments[0].samples);
+        assert_eq!(10.0, report.drift);
+    }
+
+    #[test]
+    fn test_protocol_with_one_segment_is_an_error() {
+        let protocol = DriftProtocol {
+            segments: 1,
+            ..DriftProtocol::uphill_athlete()
+        };
+        let samples = vec![HeartRateAtTime {
+            heart_rate: 1,
+            time: WARM_UP_LIMIT,
+        }];
+
+        assert_eq!(
+            Err(HeartRateDriftError::InvalidProtocol),
+            samples.heart_rate_drift(&protocol.into())
+        );
+    }
+
+    #[test]
+    fn test_averaging_can_be_time_weighted_or_unweighted() {
+        let samples = vec![
+            HeartRateAtTime {
+                heart_rate: 100,
+                time: WARM_UP_LIMIT,
+            },
+            HeartRateAtTime {
+                heart_rate: 100,
+                time: WARM_UP_LIMIT + 600.0,
+            },
+            HeartRateAtTime {
+                heart_rate: 100,
+                time: WARM_UP_LIMIT + 1200.0,
+            },
+            HeartRateAtTime {
+                heart_rate: 120,
+                time: FIRST_SEGMENT_LIMIT,
+            },
+            HeartRateAtTime {
+                heart_rate: 120,
+                time: FIRST_SEGMENT_LIMIT + 1.0,
+            },
+            HeartRateAtTime {
+                heart_rate: 120,
+                time: FIRST_SEGMENT_LIMIT + 2.0,
+            },
+            HeartRateAtTime {
+                heart_rate: 160,
+                time: LAST_SEGMENT_LIMIT - 1.0,
+            },
+        ];
+        let unweighted = DriftSettings {
+            averaging: Averaging::Unweighted,
+            ..Default::default()
+        };
+        let step_hold = DriftSettings {
+            averaging: Averaging::StepHold,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Ok(30.0),
+            samples
+                .heart_rate_drift(&unweighted)
+                .map(|result| result.drift)
+        );
+        assert_eq!(
+            Ok(20.0),
+            samples
+                .heart_rate_drift(&step_hold)
+                .map(|result| result.drift)
+        );
+    }
+
+    #[test]
+    fn test_dropout_in_a_segment_is_reported() {
+        let samples = combine_hr_with_time(
+            &[100; 4],
+            &[
+                WARM_UP_LIMIT,
+                FIRST_SEGMENT_LIMIT - 1.0,
+                FIRST_SEGMENT_LIMIT,
+                LAST_SEGMENT_LIMIT - 1.0,
+            ],
+        );
+
+        let quality = samples
+            .heart_rate_drift(&DriftSettings::default())
+            .unwrap()
+            .quality;
+
+        assert_eq!(2, quality.segments.len());
+        assert_eq!(1, quality.segments[0].gaps.len());
+    }
+
+    #[test]
+    fn test_refuses_to_calculate_when_coverage_is_below_the_minimum() {
+        let mut times: Vec<Seconds> = (900..3600).map(f64::from).collect();
+        times.push(LAST_SEGMENT_LIMIT - 1.0);
+        let samples = combine_hr_with_time(&vec![100; times.len()], &times);
+        let settings = DriftSettings {
+            quality: QualityThresholds {
+                min_coverage: Some(80.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Err(HeartRateDriftError::InsufficientCoverage {
+                segment: 2,
+                coverage: 50.0
+            }),
+            samples.heart_rate_drift(&settings)
+        );
+    }
+
+    #[test]
+    fn test_report_summarises_each_segment() {
+        let samples = combine_hr_with_time(
+            &[90, 100, 104, 110, 106],
+            &[
+                WARM_UP_LIMIT - 1.0,
+                WARM_UP_LIMIT,
+                WARM_UP_LIMIT + 1.0,
+                FIRST_SEGMENT_LIMIT,
+                FIRST_SEGMENT_LIMIT + 1.0,
+            ],
+        );
+        let settings = DriftSettings {
+            averaging: Averaging::Unweighted,
+            ..Default::default()
+        };
+
+        let report = samples.heart_rate_drift(&settings).unwrap();
+
+        assert_eq!(DriftProtocol::uphill_athlete(), report.protocol);
+        assert_eq!(2, report.segments[0].samples);
+        assert_eq!(Some(102.0), report.segments[0].average_heart_rate);
+        assert_eq!(Some(100.0), report.segments[0].min_heart_rate);
+        assert_eq!(Some(110.0), report.segments[1].max_heart_rate);
+        assert_eq!(2700, report.segments[1].start);
+        assert_eq!(Verdict::TooFast, report.verdict);
+    }
+
+    #[test]
+    fn test_accumulator_gives_the_same_report_one_sample_at_a_time() {
+        let samples: Vec<HeartRateAtTime> = (0..4800)
+            .map(|time| HeartRateAtTime {
+                heart_rate: 130 + time / 300 + time % 7,
+                time: f64::from(time),
+            })
+            .collect();
+        let settings = DriftSettings {
+            fit: Fit::TheilSen,
+            bootstrap: BootstrapOptions {
+                bootstrap: Some(50),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut accumulator = DriftAccumulator::new(&settings, Some(4799.0)).unwrap();
+        for sample in &samples {
+            accumulator.push(*sample).unwrap();
+        }
+
+        assert_eq!(samples.heart_rate_drift(&settings), accumulator.finish());
+    }
+
+    #[test]
+    fn test_accumulator_needs_no_stored_samples() {
+        let settings = DriftSettings::default();
+        let mut accumulator = DriftAccumulator::new(&settings, None).unwrap();
+        accumulator
+            .push_all((0..4500).map(|time| HeartRateAtTime {
+                heart_rate: if time < 2700 { 100 } else { 105 },
+                time: f64::from(time),
+            }))
+            .unwrap();
+
+        let report = accumulator.finish().unwrap();
+
+        assert_eq!(5.0, report.drift);
+        assert_eq!(1800, report.segments[1].samples);
+    }
+
+    #[test]
+    fn test_samples_out_of_order_give_the_same_report() {
+        let samples: Vec<HeartRateAtTime> = (0..4500)
+            .map(|time| HeartRateAtTime {
+                heart_rate: 100 + time / 450,
+                time: f64::from(time),
+            })
+            .collect();
+        let mut shuffled = samples.clone();
+        shuffled.swap(1000, 3000);
+        shuffled.swap(0, 4499);
+
+        assert_eq!(
+            samples.heart_rate_drift(&DriftSettings::default()),
+            shuffled.heart_rate_drift(&DriftSettings::default())
+        );
+    }
+
+    #[test]
+    fn test_accumulator_refuses_a_sample_back_in_time() {
+        let mut accumulator = DriftAccumulator::new(&DriftSettings::default(), None).unwrap();
+        let at = |time| HeartRateAtTime {
+            heart_rate: 100,
+            time,
+        };
+
+        assert_eq!(
+            Err(HeartRateDriftError::UnsortedTimes {
+                index: 2,
+                time: 1000.0
+            }),
+            accumulator.push_all([at(1000.0), at(3000.0), at(1000.0)])
+        );
+    }
+
+    #[test]
+    fn test_accumulator_refuses_invalid_and_negative_times() {
+        let mut accumulator = DriftAccumulator::new(&DriftSettings::default(), None).unwrap();
+        let at = |time| HeartRateAtTime {
+            heart_rate: 100,
+            time,
+        };
+
+        assert!(matches!(
+            accumulator.push(at(f64::NAN)),
+            Err(HeartRateDriftError::InvalidTime { index: 0, .. })
+        ));
+        assert_eq!(
+            Err(HeartRateDriftError::NegativeTime {
+                index: 1,
+                time: -1.0
+            }),
+            accumulator.push(at(-1.0))
+        );
+        accumulator.push(at(1000.0)).unwrap();
+        // The NaN wasn't kept as the latest time, so going back in time is still caught
+        assert_eq!(
+            Err(HeartRateDriftError::UnsortedTimes {
+                index: 3,
+                time: 500.0
+            }),
+            accumulator.push(at(500.0))
+        );
+        assert!(matches!(
+            [at(0.0), at(f64::INFINITY)].heart_rate_drift(&DriftSettings::default()),
+            Err(HeartRateDriftError::InvalidTime { index: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_accumulator_summarises_each_segment() {
+        let mut accumulator = DriftAccumulator::new(&DriftSettings::default(), None).unwrap();
+        accumulator
+            .push_all(
+                [(1000.0, 140), (1500.0, 150), (1900.0, 145)]
+                    .map(|(time, heart_rate)| HeartRateAtTime { heart_rate, time }),
+            )
+            .unwrap();
+
+        let segments = accumulator.segments();
+
+        assert_eq!(3, segments[0].samples);
+        assert_eq!(Some(145.0), segments[0].average_heart_rate);
+        assert_eq!(Some(140.0), segments[0].min_heart_rate);
+        assert_eq!(Some(150.0), segments[0].max_heart_rate);
+        // A segment without samples has no statistics
+        assert_eq!(0, segments[1].samples);
+        assert_eq!(None, segments[1].average_heart_rate);
+        assert_eq!(None, segments[1].max_heart_rate);
+    }
+
+    #[test]
+    fn test_drift_of_any_iterator() {
+        let samples = (0..4500).map(|time| HeartRateAtTime {
+            heart_rate: 100 + time / 900,
+            time: f64::from(time),
+        });
+        let mut accumulator = DriftAccumulator::new(&DriftSettings::default(), None).unwrap();
+        accumulator.push_all(samples.clone()).unwrap();
+
+        let slice: &[HeartRateAtTime] = &samples.collect::<Vec<_>>();
+        assert_eq!(
+            slice.heart_rate_drift(&DriftSettings::default()),
+            accumulator.finish()
+        );
+    }
+
+    #[test]
+    fn test_split_protocol_needs_the_end_up_front() {
+        let settings = DriftSettings::from(DriftProtocol::training_peaks());
+
+        assert_eq!(
+            Some(HeartRateDriftError::UnknownDuration),
+            DriftAccumulator::new(&settings, None).err()
+        );
     }
 }
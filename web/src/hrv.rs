@@ -0,0 +1,178 @@
+use crate::heart_rate_drift::Seconds;
+use crate::protocol::Segment;
+use crate::rr::Beat;
+use serde::Serialize;
+use std::fmt;
+
+/// Seconds the time between two beats can be off from the later beat's interval and still
+/// count as successive beats. Well under the shortest real interval, so a beat left out
+/// between them always shows.
+const SUCCESSIVE_TOLERANCE: Seconds = 0.15;
+
+/// Time-domain heart rate variability over a stretch of beats, in milliseconds
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeDomainHrv {
+    pub beats: usize,
+    pub mean_rr: f64,
+    /// Standard deviation of the intervals
+    pub sdnn: f64,
+    /// Root mean square of the differences between successive intervals
+    pub rmssd: f64,
+}
+
+/// HRV for the warm-up and each segment of the test. HRV falls as effort rises, so a big drop
+/// between the segments is another sign the test was above the aerobic threshold.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentHrv {
+    pub warm_up: Option<TimeDomainHrv>,
+    /// One per segment, missing for segments with too few beats
+    pub segments: Vec<Option<TimeDomainHrv>>,
+    /// Percentage change in RMSSD from the first segment to the last
+    pub rmssd_change: Option<f64>,
+}
+
+/// HRV of `beats`, which must be in time order. Differences are only taken between beats that
+/// directly follow each other, so there's no difference across a removed beat or a stop. None
+/// without at least one pair of successive beats.
+pub fn time_domain(beats: &[Beat]) -> Option<TimeDomainHrv> {
+    let differences: Vec<f64> = beats
+        .windows(2)
+        .filter(|pair| {
+            let elapsed = pair[1].time - pair[0].time;
+            (elapsed - pair[1].interval / 1000.0).abs() <= SUCCESSIVE_TOLERANCE
+        })
+        .map(|pair| pair[1].interval - pair[0].interval)
+        .collect();
+    if differences.is_empty() {
+        return None;
+    }
+
+    let count = beats.len() as f64;
+    let mean_rr = beats.iter().map(|beat| beat.interval).sum::<f64>() / count;
+    let variance = beats
+        .iter()
+        .map(|beat| (beat.interval - mean_rr).powi(2))
+        .sum::<f64>()
+        / (count - 1.0);
+    let squares = differences.iter().map(|difference| difference.powi(2));
+    Some(TimeDomainHrv {
+        beats: beats.len(),
+        mean_rr,
+        sdnn: variance.sqrt(),
+        rmssd: (squares.sum::<f64>() / differences.len() as f64).sqrt(),
+    })
+}
+
+impl SegmentHrv {
+    /// `beats` are on the same clock as the segments, and in time order
+    pub fn new(beats: &[Beat], warm_up: &Segment, segments: &[Segment]) -> Self {
+        let within = |segment: &Segment| {
+            let from = beats.partition_point(|beat| beat.time < f64::from(segment.start));
+            let to = beats.partition_point(|beat| beat.time < f64::from(segment.end));
+            time_domain(&beats[from..to])
+        };
+        let segments: Vec<Option<TimeDomainHrv>> = segments.iter().map(within).collect();
+        let rmssd_change = match (segments.first(), segments.last()) {
+            (Some(Some(first)), Some(Some(last))) if first.rmssd > 0.0 => {
+                Some((last.rmssd - first.rmssd) / first.rmssd * 100.0)
+            }
+            _ => None,
+        };
+
+        Self {
+            warm_up: within(warm_up),
+            segments,
+            rmssd_change,
+        }
+    }
+}
+
+impl fmt::Display for TimeDomainHrv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mean RR {:.0} ms, SDNN {:.1} ms, RMSSD {:.1} ms",
+            self.mean_rr, self.sdnn, self.rmssd
+        )
+    }
+}
+
+impl fmt::Display for SegmentHrv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let describe = |hrv: &Option<TimeDomainHrv>| match hrv {
+            Some(hrv) => hrv.to_string(),
+            None => "not enough beats".to_string(),
+        };
+        write!(f, "HRV in the warm-up: {}", describe(&self.warm_up))?;
+        for (index, hrv) in self.segments.iter().enumerate() {
+            write!(f, "\nHRV in segment {}: {}", index + 1, describe(hrv))?;
+        }
+        if let Some(rmssd_change) = self.rmssd_change {
+            write!(
+                f,
+                "\nRMSSD changed {rmssd_change:+.0}% from the first segment to the last"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rr::{beats_from_intervals, clean_beats, RrOptions};
+
+    #[test]
+    fn test_time_domain_of_alternating_intervals() {
+        let beats = beats_from_intervals(&[800.0, 820.0, 800.0, 820.0]);
+
+        let hrv = time_domain(&beats).unwrap();
+
+        assert_eq!(810.0, hrv.mean_rr);
+        assert_eq!(20.0, hrv.rmssd);
+        assert_eq!((400.0_f64 / 3.0).sqrt(), hrv.sdnn);
+    }
+
+    #[test]
+    fn test_no_difference_is_taken_across_a_removed_beat() {
+        let intervals = [800.0; 10]
+            .into_iter()
+            .chain([500.0, 1100.0])
+            .chain([840.0; 10])
+            .collect::<Vec<f64>>();
+        let clean = clean_beats(&beats_from_intervals(&intervals), &RrOptions::default());
+
+        let hrv = time_domain(&clean.beats).unwrap();
+
+        assert_eq!(0.0, hrv.rmssd);
+        assert_eq!(20, hrv.beats);
+    }
+
+    #[test]
+    fn test_segments_are_compared_with_the_first() {
+        let steady: Vec<f64> = (0..1200)
+            .map(|beat| if beat % 2 == 0 { 875.0 } else { 625.0 })
+            .collect();
+        let strained: Vec<f64> = (0..1200)
+            .map(|beat| if beat % 2 == 0 { 500.0 } else { 562.5 })
+            .collect();
+        // One beat in the warm-up, then the steady beats up to 900.5s
+        let beats = beats_from_intervals(&[vec![500.0], steady, strained].concat());
+        let warm_up = Segment { start: 0, end: 1 };
+        let segments = [
+            Segment { start: 1, end: 901 },
+            Segment {
+                start: 901,
+                end: 1500,
+            },
+        ];
+
+        let hrv = SegmentHrv::new(&beats, &warm_up, &segments);
+
+        assert_eq!(None, hrv.warm_up);
+        assert_eq!(Some(750.0), hrv.segments[0].map(|hrv| hrv.mean_rr));
+        assert_eq!(Some(-75.0), hrv.rmssd_change);
+    }
+}
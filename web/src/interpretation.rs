@@ -0,0 +1,152 @@
+use crate::decoupling::DecouplingResult;
+use crate::report::{Verdict, TOO_FAST_DRIFT, TOO_SLOW_DRIFT};
+use serde::Serialize;
+use std::fmt;
+
+/// Suggestions aim for the middle of the 3.5-5% range
+const TARGET_DRIFT: f64 = (TOO_FAST_DRIFT + TOO_SLOW_DRIFT) / 2.0;
+
+/// What to do differently next time the test is run
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NextTest {
+    /// Heart rate to hold at the start of the next test
+    pub heart_rate: f64,
+    /// Change from this test's first segment, in beats per minute
+    pub heart_rate_change: f64,
+    /// Average speed or power to aim for, when pace or power was recorded
+    pub output: Option<f64>,
+}
+
+/// What a drift result means for the athlete's aerobic threshold (AeT)
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Interpretation {
+    pub verdict: Verdict,
+    /// The percentage the verdict is based on. Decoupling when pace or power was recorded,
+    /// since that doesn't assume the pace was held, and heart rate drift otherwise.
+    pub percentage: f64,
+    /// When the test was in range, AeT is right around the heart rate at the start of the test
+    pub aerobic_threshold: Option<f64>,
+    /// When the test was out of range, where to start the next one
+    pub next_test: Option<NextTest>,
+}
+
+impl Interpretation {
+    /// `first_heart_rate` is the average heart rate of the first segment.
+    ///
+    /// The suggestion assumes that near AeT the rise in heart rate over the test scales with how
+    /// hard it started, so it moves the starting heart rate by the number of beats the drift was
+    /// away from the middle of the range. Output is moved in proportion to heart rate. It's a
+    /// starting point for the next test, not a replacement for it.
+    pub fn new(drift: f64, first_heart_rate: f64, decoupling: Option<&DecouplingResult>) -> Self {
+        let percentage = decoupling.map_or(drift, |result| result.decoupling);
+        let verdict = Verdict::from_drift(percentage);
+
+        if verdict == Verdict::InRange {
+            return Self {
+                verdict,
+                percentage,
+                aerobic_threshold: Some(first_heart_rate),
+                next_test: None,
+            };
+        }
+
+        let heart_rate_change = first_heart_rate * (TARGET_DRIFT - percentage) / 100.0;
+        let heart_rate = first_heart_rate + heart_rate_change;
+        let output = decoupling
+            .map(|result| result.first_segment.average_output * heart_rate / first_heart_rate);
+        Self {
+            verdict,
+            percentage,
+            aerobic_threshold: None,
+            next_test: Some(NextTest {
+                heart_rate,
+                heart_rate_change,
+                output,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Interpretation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}% is {}. ", self.percentage, self.verdict)?;
+        match (self.aerobic_threshold, self.next_test) {
+            (Some(aerobic_threshold), _) => write!(
+                f,
+                "Your aerobic threshold is around {aerobic_threshold:.0} bpm"
+            ),
+            (None, Some(next_test)) => write!(
+                f,
+                "Next time hold around {:.0} bpm ({:+.0} bpm) at the start of the test",
+                next_test.heart_rate, next_test.heart_rate_change
+            ),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoupling::SegmentEfficiency;
+
+    fn decoupling_of(percentage: f64, first_output: f64) -> DecouplingResult {
+        let segment = SegmentEfficiency {
+            average_heart_rate: 140.0,
+            average_output: first_output,
+            efficiency_factor: first_output / 140.0,
+        };
+        DecouplingResult {
+            first_segment: segment,
+            last_segment: segment,
+            decoupling: percentage,
+        }
+    }
+
+    #[test]
+    fn test_in_range_drift_estimates_aerobic_threshold_from_the_first_segment() {
+        let interpretation = Interpretation::new(4.0, 142.5, None);
+
+        assert_eq!(Verdict::InRange, interpretation.verdict);
+        assert_eq!(Some(142.5), interpretation.aerobic_threshold);
+        assert_eq!(None, interpretation.next_test);
+    }
+
+    #[test]
+    fn test_too_fast_suggests_a_lower_heart_rate() {
+        let interpretation = Interpretation::new(8.25, 100.0, None);
+
+        assert_eq!(None, interpretation.aerobic_threshold);
+        assert_eq!(
+            Some(NextTest {
+                heart_rate: 96.0,
+                heart_rate_change: -4.0,
+                output: None
+            }),
+            interpretation.next_test
+        );
+    }
+
+    #[test]
+    fn test_too_slow_suggests_a_higher_heart_rate_and_pace() {
+        let decoupling = decoupling_of(0.25, 3.0);
+
+        let interpretation = Interpretation::new(0.25, 100.0, Some(&decoupling));
+
+        let next_test = interpretation.next_test.unwrap();
+        assert_eq!(4.0, next_test.heart_rate_change);
+        assert_eq!(Some(3.0 * 104.0 / 100.0), next_test.output);
+    }
+
+    #[test]
+    fn test_decoupling_takes_priority_over_heart_rate_drift() {
+        let decoupling = decoupling_of(4.0, 3.0);
+
+        let interpretation = Interpretation::new(7.0, 140.0, Some(&decoupling));
+
+        assert_eq!(Verdict::InRange, interpretation.verdict);
+        assert_eq!(4.0, interpretation.percentage);
+    }
+}
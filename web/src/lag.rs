@@ -0,0 +1,213 @@
+use crate::decoupling::EffortAtTime;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Seconds over which changes in heart rate and output are compared when estimating the lag
+const CHANGE_SPAN: usize = 10;
+/// Below this correlation heart rate doesn't clearly follow output, as on a perfectly steady
+/// run, and an estimated lag would be noise
+const MIN_CORRELATION: f64 = 0.2;
+
+/// How far heart rate is shifted to line up with pace or power, given or estimated
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LagOptions {
+    /// Seconds heart rate lags behind pace or power. Each output is paired with the heart rate
+    /// this long after it before the segments are laid over them
+    #[arg(long)]
+    pub lag: Option<i32>,
+    /// Estimate the lag by cross-correlating heart rate with pace or power, instead of giving it
+    #[arg(long, conflicts_with = "lag")]
+    pub estimate_lag: bool,
+    /// Longest lag, in seconds, the estimate will consider
+    #[arg(long, default_value_t = LagOptions::default().max_lag)]
+    pub max_lag: i32,
+}
+
+impl Default for LagOptions {
+    fn default() -> Self {
+        Self {
+            lag: None,
+            estimate_lag: false,
+            max_lag: 90,
+        }
+    }
+}
+
+/// The shift applied to heart rate before decoupling was calculated
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CardiacLag {
+    pub seconds: i32,
+    /// Correlation between output and the shifted heart rate, when the lag was estimated
+    pub correlation: Option<f64>,
+}
+
+impl LagOptions {
+    /// The lag to apply to `efforts`, if any. None when it was to be estimated and heart rate
+    /// doesn't follow output at all.
+    pub fn cardiac_lag(&self, efforts: &[EffortAtTime]) -> Option<CardiacLag> {
+        if self.estimate_lag {
+            estimate_lag(efforts, self.max_lag)
+        } else {
+            self.lag.map(|seconds| CardiacLag {
+                seconds,
+                correlation: None,
+            })
+        }
+    }
+}
+
+/// Pairs each output with the heart rate `lag` seconds later, which is when the heart has caught
+/// up with it. Outputs near the end that have no heart rate that far after them are dropped.
+pub fn shift_heart_rate(efforts: &[EffortAtTime], lag: i32) -> Vec<EffortAtTime> {
+    efforts
+        .iter()
+        .filter_map(|effort| {
+            let later = efforts.partition_point(|other| other.time < effort.time + f64::from(lag));
+            efforts.get(later).map(|later| EffortAtTime {
+                heart_rate: later.heart_rate,
+                ..*effort
+            })
+        })
+        .collect()
+}
+
+/// The lag, up to `max_lag` seconds, at which changes in heart rate correlate best with changes
+/// in output. Comparing changes rather than levels stops the slow rise of drift itself from
+/// looking like a long lag. Samples are held to a one second grid first so smart recording
+/// doesn't skew the correlation.
+pub fn estimate_lag(efforts: &[EffortAtTime], max_lag: i32) -> Option<CardiacLag> {
+    let (first, last) = (efforts.first()?, efforts.last()?);
+    let mut heart_rates = Vec::new();
+    let mut outputs = Vec::new();
+    let mut next = 0;
+    for time in first.time.floor() as i32..=last.time.floor() as i32 {
+        let time = f64::from(time);
+        while next + 1 < efforts.len() && efforts[next + 1].time <= time {
+            next += 1;
+        }
+        heart_rates.push(f64::from(efforts[next].heart_rate));
+        outputs.push(efforts[next].output);
+    }
+    let changes = |values: &[f64]| -> Vec<f64> {
+        values
+            .windows(CHANGE_SPAN + 1)
+            .map(|window| window[CHANGE_SPAN] - window[0])
+            .collect()
+    };
+    let (heart_rates, outputs) = (changes(&heart_rates), changes(&outputs));
+
+    (0..=max_lag.max(0) as usize)
+        .take_while(|&lag| lag + 2 <= outputs.len())
+        .filter_map(|lag| {
+            correlation(&outputs[..outputs.len() - lag], &heart_rates[lag..]).map(|correlation| {
+                CardiacLag {
+                    seconds: lag as i32,
+                    correlation: Some(correlation),
+                }
+            })
+        })
+        .reduce(|best, lag| {
+            if lag.correlation > best.correlation {
+                lag
+            } else {
+                best
+            }
+        })
+        .filter(|lag| lag.correlation >= Some(MIN_CORRELATION))
+}
+
+/// Pearson correlation. None when either side doesn't vary.
+fn correlation(first: &[f64], second: &[f64]) -> Option<f64> {
+    let count = first.len() as f64;
+    let mean_first = first.iter().sum::<f64>() / count;
+    let mean_second = second.iter().sum::<f64>() / count;
+    let (mut covariance, mut variance_first, mut variance_second) = (0.0, 0.0, 0.0);
+    for (a, b) in first.iter().zip(second) {
+        covariance += (a - mean_first) * (b - mean_second);
+        variance_first += (a - mean_first).powi(2);
+        variance_second += (b - mean_second).powi(2);
+    }
+    (variance_first > 0.0 && variance_second > 0.0)
+        .then(|| covariance / (variance_first * variance_second).sqrt())
+}
+
+impl fmt::Display for CardiacLag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Heart rate shifted back {}s to line up with output",
+            self.seconds
+        )?;
+        if let Some(correlation) = self.correlation {
+            write!(f, " (estimated, correlation {correlation:.2})")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output alternating between easy and hard every five minutes, with heart rate following
+    /// `lag` seconds later
+    fn intervals(lag: i32) -> Vec<EffortAtTime> {
+        let hard = |time: i32| time >= 0 && (time / 300) % 2 == 1;
+        (0..3600)
+            .map(|time| EffortAtTime {
+                heart_rate: if hard(time - lag) { 150 } else { 130 },
+                output: if hard(time) { 3.0 } else { 2.0 },
+                time: f64::from(time),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shift_pairs_output_with_later_heart_rate() {
+        let shifted = shift_heart_rate(&intervals(40), 40);
+
+        assert_eq!(3560, shifted.len());
+        assert!(shifted
+            .iter()
+            .all(|effort| (effort.output == 3.0) == (effort.heart_rate == 150)));
+    }
+
+    #[test]
+    fn test_estimates_the_lag_from_intervals() {
+        let lag = estimate_lag(&intervals(40), 90).unwrap();
+
+        assert_eq!(40, lag.seconds);
+        assert!(lag.correlation.unwrap() > 0.999);
+    }
+
+    #[test]
+    fn test_steady_output_has_no_lag_to_find() {
+        let efforts: Vec<EffortAtTime> = (0..600)
+            .map(|time| EffortAtTime {
+                heart_rate: 140 + time / 60,
+                output: 3.0,
+                time: f64::from(time),
+            })
+            .collect();
+
+        assert_eq!(None, estimate_lag(&efforts, 90));
+    }
+
+    #[test]
+    fn test_given_lag_is_used_as_is() {
+        let options = LagOptions {
+            lag: Some(30),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Some(CardiacLag {
+                seconds: 30,
+                correlation: None
+            }),
+            options.cardiac_lag(&intervals(30))
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::heart_rate_drift::{HeartRateDriftError, Seconds};
+use crate::moving::MovingTime;
+use crate::protocol::{DriftProtocol, Segment};
+use crate::validation::StreamOrder;
+use serde::Deserialize;
+
+const SECONDS_PER_MINUTE: i32 = 60;
+
+/// A lap as Strava's `/activities/{id}/laps` endpoint returns it. The indices point into the
+/// activity's streams, and the last sample of one lap is usually the first of the next.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct Lap {
+    #[serde(alias = "startIndex")]
+    pub start_index: usize,
+    #[serde(alias = "endIndex")]
+    pub end_index: usize,
+}
+
+/// Which laps are the warm-up, and how short a test lap can be
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LapOptions {
+    /// Laps before the test starts. The laps after them are the segments
+    #[arg(long, default_value_t = LapOptions::default().warm_up_laps)]
+    pub warm_up_laps: usize,
+    /// Minutes every test lap has to last, to catch a lap button pressed by accident
+    #[arg(long, default_value_t = LapOptions::default().min_lap_length)]
+    pub min_lap_length: i32,
+}
+
+impl Default for LapOptions {
+    fn default() -> Self {
+        Self {
+            warm_up_laps: 1,
+            min_lap_length: 10,
+        }
+    }
+}
+
+/// Each lap's window in the activity's `times`, in whole seconds, ending where the next lap
+/// starts
+pub fn lap_windows(laps: &[Lap], times: &[Seconds]) -> Vec<Segment> {
+    let time_at = |index: usize| times[index.min(times.len().saturating_sub(1))].floor() as i32;
+    laps.iter()
+        .enumerate()
+        .filter(|_| !times.is_empty())
+        .map(|(index, lap)| Segment {
+            start: time_at(lap.start_index),
+            end: laps
+                .get(index + 1)
+                .map_or(time_at(lap.end_index) + 1, |next| time_at(next.start_index)),
+        })
+        .collect()
+}
+
+/// The laps with their indices moved to point into the streams once the stops are taken out
+pub fn laps_in_moving_time(laps: &[Lap], moving_time: &MovingTime) -> Vec<Lap> {
+    move_laps(laps, |index| moving_time.kept_before(index))
+}
+
+/// The laps with their indices moved to follow the streams once validation has repaired them.
+/// Each boundary follows its own sample, wherever sorting put it, or the next sample recorded
+/// after it when it was dropped.
+pub fn laps_in_stream_order(laps: &[Lap], order: &StreamOrder) -> Vec<Lap> {
+    let positions = order.positions();
+    move_laps(laps, |index| {
+        positions
+            .get(index..)
+            .and_then(|after| after.iter().flatten().next().copied())
+            .unwrap_or(order.indices.len())
+    })
+}
+
+/// `position` gives where the sample at an index ends up, or the next one kept if it's gone
+fn move_laps(laps: &[Lap], position: impl Fn(usize) -> usize) -> Vec<Lap> {
+    laps.iter()
+        .map(|lap| Lap {
+            start_index: position(lap.start_index),
+            end_index: position(lap.end_index + 1).saturating_sub(1),
+        })
+        .collect()
+}
+
+/// A protocol whose segments are the `segments` laps after the warm-up laps, laid over `times`.
+/// Laps after those, like a cool-down, are ignored.
+pub fn protocol_from_laps(
+    laps: &[Lap],
+    times: &[Seconds],
+    segments: usize,
+    options: &LapOptions,
+) -> Result<DriftProtocol, HeartRateDriftError> {
+    let windows = lap_windows(laps, times);
+    let needed = options.warm_up_laps + segments;
+    if windows.len() < needed || segments < 2 {
+        return Err(HeartRateDriftError::NotEnoughLaps {
+            needed,
+            found: windows.len(),
+        });
+    }
+    let test_laps = &windows[options.warm_up_laps..needed];
+    let min_length = options.min_lap_length * SECONDS_PER_MINUTE;
+    if let Some((index, lap)) = test_laps
+        .iter()
+        .enumerate()
+        .find(|(_, lap)| lap.end - lap.start < min_length)
+    {
+        return Err(HeartRateDriftError::LapTooShort {
+            lap: options.warm_up_laps + index + 1,
+            length: lap.end - lap.start,
+        });
+    }
+    Ok(DriftProtocol::from_laps(test_laps.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{validate_streams, Repair};
+
+    fn laps() -> Vec<Lap> {
+        vec![
+            Lap {
+                start_index: 0,
+                end_index: 900,
+            },
+            Lap {
+                start_index: 900,
+                end_index: 2700,
+            },
+            Lap {
+                start_index: 2700,
+                end_index: 4400,
+            },
+            Lap {
+                start_index: 4400,
+                end_index: 4999,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_laps_become_windows_in_activity_time() {
+        let times: Vec<Seconds> = (0..5000).map(|index| f64::from(index * 2)).collect();
+
+        let windows = lap_windows(&laps(), &times);
+
+        assert_eq!(
+            vec![
+                Segment {
+                    start: 0,
+                    end: 1800
+                },
+                Segment {
+                    start: 1800,
+                    end: 5400
+                },
+                Segment {
+                    start: 5400,
+                    end: 8800
+                },
+                Segment {
+                    start: 8800,
+                    end: 9999
+                },
+            ],
+            windows
+        );
+    }
+
+    #[test]
+    fn test_segments_are_the_laps_after_the_warm_up() {
+        let times: Vec<Seconds> = (0..5000).map(f64::from).collect();
+
+        let protocol = protocol_from_laps(&laps(), &times, 2, &LapOptions::default()).unwrap();
+
+        assert_eq!(900, protocol.warm_up);
+        assert_eq!(
+            vec![
+                Segment {
+                    start: 900,
+                    end: 2700
+                },
+                Segment {
+                    start: 2700,
+                    end: 4400
+                }
+            ],
+            protocol.windows(4999.0)
+        );
+    }
+
+    #[test]
+    fn test_short_lap_is_an_error() {
+        let times: Vec<Seconds> = (0..5000).map(f64::from).collect();
+        let options = LapOptions {
+            min_lap_length: 30,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Err(HeartRateDriftError::LapTooShort {
+                lap: 3,
+                length: 1700
+            }),
+            protocol_from_laps(&laps(), &times, 2, &options)
+        );
+    }
+
+    #[test]
+    fn test_lap_boundary_follows_its_sample_when_sorting() {
+        // The lap button was pressed at 2s, whose sample was recorded after the one at 3s
+        let times = [0.0, 1.0, 3.0, 2.0, 4.0, 5.0];
+        let laps = [
+            Lap {
+                start_index: 0,
+                end_index: 3,
+            },
+            Lap {
+                start_index: 3,
+                end_index: 5,
+            },
+        ];
+        let order = validate_streams(&times, &[], &[Repair::Sort]).unwrap();
+
+        let laps = laps_in_stream_order(&laps, &order);
+
+        assert_eq!(
+            vec![Segment { start: 0, end: 2 }, Segment { start: 2, end: 6 }],
+            lap_windows(&laps, &order.apply(&times))
+        );
+    }
+
+    #[test]
+    fn test_too_few_laps_is_an_error() {
+        let times: Vec<Seconds> = (0..5000).map(f64::from).collect();
+
+        assert_eq!(
+            Err(HeartRateDriftError::NotEnoughLaps {
+                needed: 3,
+                found: 1
+            }),
+            protocol_from_laps(&laps()[..1], &times, 2, &LapOptions::default())
+        );
+    }
+}
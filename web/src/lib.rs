@@ -1,15 +1,254 @@
-mod heart_rate_drift;
+pub mod analysis;
+pub mod averaging;
+pub mod bootstrap;
+pub mod decoupling;
+pub mod detection;
+pub mod dfa;
+pub mod filters;
+pub mod fit;
+pub mod grade;
+pub mod heart_rate_drift;
+pub mod hrv;
+pub mod interpretation;
+pub mod lag;
+pub mod laps;
+pub mod live;
+pub mod moving;
+pub mod protocol;
+pub mod quality;
+pub mod report;
+pub mod rolling;
+pub mod rr;
+pub mod strava;
+pub mod temperature;
+pub mod trend;
+pub mod validation;
 
-use heart_rate_drift::{combine_hr_with_time, HeartRateDrift};
+use analysis::{AnalysisOptions, Streams};
+use fit::read_fit;
+use laps::Lap;
+use rolling::{rolling_drift, RollingOptions};
+use rr::beats_from_intervals;
+use serde::Deserialize;
 use wasm_bindgen::prelude::*;
 
+/// Options passed from JavaScript as a JSON string, e.g.
+/// `{"protocol": "friel", "warmUp": 20, "averaging": "step-hold", "fit": "theil-sen", "bootstrap": 1000, "mode": "power"}`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Options {
+    #[serde(flatten)]
+    analysis: AnalysisOptions,
+    #[serde(flatten)]
+    rolling: RollingOptions,
+    /// Laps from Strava's laps endpoint. When given, the test segments are laps rather than
+    /// fixed offsets
+    laps: Option<Vec<Lap>>,
+}
+
+fn parse_options(options: Option<String>) -> Result<Options, JsError> {
+    Ok(match options {
+        Some(json) => serde_json::from_str(&json)?,
+        None => Options::default(),
+    })
+}
+
+/// The streams of an activity, handed over from JavaScript. Every stream except the RR
+/// intervals has a value for each time. Only the streams that are there need setting, e.g.
+/// `const streams = new ActivityStreams(heartRates, times); streams.speeds = speeds;`
+#[wasm_bindgen]
+pub struct ActivityStreams {
+    heart_rates: Vec<i32>,
+    times: Vec<f64>,
+    speeds: Option<Vec<f64>>,
+    watts: Option<Vec<f64>>,
+    cadence: Option<Vec<f64>>,
+    moving: Option<Vec<u8>>,
+    altitudes: Option<Vec<f64>>,
+    distances: Option<Vec<f64>>,
+    temperatures: Option<Vec<f64>>,
+    rr_intervals: Option<Vec<f64>>,
+}
+
+#[wasm_bindgen]
+impl ActivityStreams {
+    /// `times` are seconds, and can be fractional for data timed more finely than a second
+    #[wasm_bindgen(constructor)]
+    pub fn new(heart_rates: Vec<i32>, times: Vec<f64>) -> Self {
+        Self {
+            heart_rates,
+            times,
+            speeds: None,
+            watts: None,
+            cadence: None,
+            moving: None,
+            altitudes: None,
+            distances: None,
+            temperatures: None,
+            rr_intervals: None,
+        }
+    }
+
+    /// The streams of a FIT file, handed over as a `Uint8Array`, e.g.
+    /// `ActivityStreams.fromFit(new Uint8Array(await file.arrayBuffer()))`
+    #[wasm_bindgen(js_name = fromFit)]
+    pub fn from_fit(bytes: &[u8]) -> Result<ActivityStreams, JsError> {
+        let streams = read_fit(bytes)?;
+        Ok(Self {
+            heart_rates: streams.heart_rates,
+            times: streams.times,
+            speeds: streams.speeds,
+            watts: streams.watts,
+            cadence: streams.cadence,
+            moving: None,
+            altitudes: streams.altitudes,
+            distances: streams.distances,
+            temperatures: streams.temperatures,
+            rr_intervals: streams
+                .beats
+                .map(|beats| beats.iter().map(|beat| beat.interval).collect()),
+        })
+    }
+
+    /// Metres per second, for pace mode. Worked out from `distances` when not set
+    #[wasm_bindgen(setter)]
+    pub fn set_speeds(&mut self, speeds: Vec<f64>) {
+        self.speeds = Some(speeds);
+    }
+
+    /// For power mode
+    #[wasm_bindgen(setter)]
+    pub fn set_watts(&mut self, watts: Vec<f64>) {
+        self.watts = Some(watts);
+    }
+
+    /// Only needed by the cadence lock filter
+    #[wasm_bindgen(setter)]
+    pub fn set_cadence(&mut self, cadence: Vec<f64>) {
+        self.cadence = Some(cadence);
+    }
+
+    /// Strava's moving stream as 0s and 1s, for `movingTime`
+    #[wasm_bindgen(setter)]
+    pub fn set_moving(&mut self, moving: Vec<u8>) {
+        self.moving = Some(moving);
+    }
+
+    /// Metres above sea level, for vertical mode and `gradeAdjusted`
+    #[wasm_bindgen(setter)]
+    pub fn set_altitudes(&mut self, altitudes: Vec<f64>) {
+        self.altitudes = Some(altitudes);
+    }
+
+    /// Cumulative metres, for `gradeAdjusted`
+    #[wasm_bindgen(setter)]
+    pub fn set_distances(&mut self, distances: Vec<f64>) {
+        self.distances = Some(distances);
+    }
+
+    /// The temp stream in °C
+    #[wasm_bindgen(setter)]
+    pub fn set_temperatures(&mut self, temperatures: Vec<f64>) {
+        self.temperatures = Some(temperatures);
+    }
+
+    /// Beat-to-beat intervals in milliseconds from the start of the activity. They don't line
+    /// up with `times`.
+    #[wasm_bindgen(setter = rrIntervals)]
+    pub fn set_rr_intervals(&mut self, rr_intervals: Vec<f64>) {
+        self.rr_intervals = Some(rr_intervals);
+    }
+}
+
+impl ActivityStreams {
+    fn to_streams(&self, laps: Option<Vec<Lap>>) -> Streams {
+        Streams {
+            heart_rates: self.heart_rates.clone(),
+            times: self.times.clone(),
+            speeds: self.speeds.clone(),
+            distances: self.distances.clone(),
+            watts: self.watts.clone(),
+            cadence: self.cadence.clone(),
+            moving: self
+                .moving
+                .as_ref()
+                .map(|moving| moving.iter().map(|&moving| moving != 0).collect()),
+            altitudes: self.altitudes.clone(),
+            temperatures: self.temperatures.clone(),
+            laps,
+            beats: self.rr_intervals.as_deref().map(beats_from_intervals),
+        }
+    }
+}
+
+/// Returns the drift report as JSON. Pace mode compares heart rate against the speeds, power
+/// mode against the watts, and vertical mode against the ascent rate worked out from the
+/// altitudes. With `detectWindow` any of those also help find the steadiest stretch. With
+/// `movingTime`, stops are found from the moving stream, or from the speeds when there isn't
+/// one. `gradeAdjusted` only works in pace mode, and adds decoupling from grade-adjusted pace
+/// using the altitudes and distances. Temperatures are reported per segment along with any
+/// `ambientTemperature`. Streams that don't line up with the times, and times out of order or
+/// repeated, are an error unless `repairs` (`"sort"`, `"dedupe"`, `"truncate"`) allows fixing
+/// them. From RR intervals the report adds where DFA α1 crosses 0.75 and the HRV of the warm-up
+/// and each segment.
+#[wasm_bindgen]
+pub fn calculate_heart_rate_drift(
+    streams: &ActivityStreams,
+    options: Option<String>,
+) -> Result<String, JsError> {
+    let options = parse_options(options)?;
+    let prepared = streams
+        .to_streams(options.laps)
+        .prepare(&options.analysis)?;
+    if !prepared.repairs.is_empty() {
+        log(&prepared.repairs.to_string());
+    }
+    if !options.analysis.filters.is_empty() {
+        log(&prepared.filters.to_string());
+    }
+    let report = prepared.report(&options.analysis)?;
+    Ok(serde_json::to_string(&report)?)
+}
+
+/// Rolling drift, with the columns handed to JavaScript as `Float64Array`s
+#[wasm_bindgen]
+pub struct RollingDriftSeries {
+    times: Vec<f64>,
+    drifts: Vec<f64>,
+}
+
+#[wasm_bindgen]
+impl RollingDriftSeries {
+    #[wasm_bindgen(getter)]
+    pub fn times(&self) -> Vec<f64> {
+        self.times.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn drifts(&self) -> Vec<f64> {
+        self.drifts.clone()
+    }
+}
+
+/// Drift over time across the whole activity, for charting where decoupling starts. Takes the
+/// same options as `calculate_heart_rate_drift`, of which `rollingWindow`, `rollingStep`,
+/// `averaging`, `filters`, `repairs` and `movingTime` apply.
 #[wasm_bindgen]
-pub fn calculate_heart_rate_drift(heart_rates: &[i32], times: &[i32]) {
-    let drifts = combine_hr_with_time(heart_rates, times);
-    log(&format!(
-        "Heart rate drift is {:#?}",
-        drifts.heart_rate_drift()
-    ));
+pub fn calculate_rolling_drift(
+    streams: &ActivityStreams,
+    options: Option<String>,
+) -> Result<RollingDriftSeries, JsError> {
+    let options = parse_options(options)?;
+    let prepared = streams.to_streams(None).prepare(&options.analysis)?;
+    let rolling = rolling_drift(
+        &prepared.samples,
+        &options.rolling,
+        options.analysis.averaging,
+    );
+    Ok(RollingDriftSeries {
+        times: rolling.times,
+        drifts: rolling.drifts,
+    })
 }
 
 #[wasm_bindgen]
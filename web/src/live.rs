@@ -0,0 +1,220 @@
+use crate::heart_rate_drift::{
+    BeatsPerMinute, DriftAccumulator, DriftSettings, HeartRateAtTime, HeartRateDriftError, Seconds,
+};
+use crate::report::{minutes_and_seconds, DriftReport, SegmentSummary};
+use serde::Serialize;
+use std::fmt;
+
+/// Follows drift while the workout is still going, taking samples as a strap sends them. The
+/// segments are laid out up front, so the protocol needs a fixed length or the workout a planned
+/// duration.
+#[derive(Clone, Debug)]
+pub struct LiveDrift {
+    accumulator: DriftAccumulator,
+    /// Where the last segment ends, after which the test is over
+    end: i32,
+    latest: Option<HeartRateAtTime>,
+}
+
+/// Where the test stands after the latest sample
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LiveUpdate {
+    pub time: Seconds,
+    pub heart_rate: BeatsPerMinute,
+    /// The segment the latest sample is in, counting from 1. None during the warm-up and after
+    /// the test.
+    pub segment: Option<usize>,
+    /// Averaged over the samples so far, so segments still to come have none
+    pub segments: Vec<SegmentSummary>,
+    /// Drift between the averages so far, once the last segment has started
+    pub drift: Option<f64>,
+    /// The drift the test ends up with if heart rate keeps rising the way it has so far, from
+    /// the trend between the middles of the first and last segments
+    pub projected_drift: Option<f64>,
+}
+
+impl LiveDrift {
+    /// `duration` is the planned length of the workout in seconds. It's only needed when the
+    /// protocol splits the whole activity, but it overrides the protocol's own length if given.
+    pub fn new(
+        settings: &DriftSettings,
+        duration: Option<i32>,
+    ) -> Result<Self, HeartRateDriftError> {
+        // The windows are laid over the time of the last sample, which is a second before the
+        // end of a workout recorded every second
+        let accumulator =
+            DriftAccumulator::new(settings, duration.map(|duration| f64::from(duration - 1)))?;
+        let end = accumulator
+            .segments()
+            .last()
+            .map_or(0, |segment| segment.end);
+        Ok(Self {
+            accumulator,
+            end,
+            latest: None,
+        })
+    }
+
+    /// Adds the next sample. Samples can't be put back in order live, so one at or before the
+    /// latest is refused, as is a negative or invalid time.
+    pub fn push(&mut self, sample: HeartRateAtTime) -> Result<(), HeartRateDriftError> {
+        if let Some(latest) = self.latest.filter(|latest| sample.time <= latest.time) {
+            return Err(HeartRateDriftError::LateSample {
+                time: sample.time,
+                latest: latest.time,
+            });
+        }
+        self.accumulator.push(sample)?;
+        self.latest = Some(sample);
+        Ok(())
+    }
+
+    /// True once a sample has arrived after the last segment
+    pub fn is_over(&self) -> bool {
+        self.latest
+            .is_some_and(|latest| latest.time >= f64::from(self.end))
+    }
+
+    /// Where the test stands, or None before the first sample
+    pub fn update(&self) -> Option<LiveUpdate> {
+        let latest = self.latest?;
+        let segments = self.accumulator.segments();
+        let segment = segments
+            .iter()
+            .position(|segment| {
+                latest.time >= f64::from(segment.start) && latest.time < f64::from(segment.end)
+            })
+            .map(|index| index + 1);
+        let drift = match (
+            segments[0].average_heart_rate,
+            segments[segments.len() - 1].average_heart_rate,
+        ) {
+            (Some(first), Some(last)) => Some((last - first) / first * 100.0),
+            _ => None,
+        };
+        Some(LiveUpdate {
+            time: latest.time,
+            heart_rate: latest.heart_rate,
+            segment,
+            segments,
+            drift,
+            projected_drift: self.accumulator.trend().map(|trend| trend.drift),
+        })
+    }
+
+    /// The report for the test so far. Any of the segments that hasn't been reached yet counts
+    /// as a gap in the quality check.
+    pub fn finish(self) -> Result<DriftReport, HeartRateDriftError> {
+        self.accumulator.finish()
+    }
+}
+
+impl fmt::Display for LiveUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} bpm",
+            minutes_and_seconds(self.time.floor() as i32),
+            self.heart_rate
+        )?;
+        match self.segment {
+            Some(segment) => write!(f, ", segment {segment} of {}", self.segments.len())?,
+            None => write!(f, ", outside the test")?,
+        }
+        let averages: Vec<String> = self
+            .segments
+            .iter()
+            .map(|segment| match segment.average_heart_rate {
+                Some(average) => format!("{average:.1}"),
+                None => "-".to_string(),
+            })
+            .collect();
+        write!(f, ", averages {}", averages.join(" / "))?;
+        if let Some(drift) = self.drift {
+            write!(f, ", drift {drift:.2}%")?;
+        }
+        if let Some(projected_drift) = self.projected_drift {
+            write!(f, ", projected {projected_drift:.2}%")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DriftProtocol;
+
+    fn rising_from(time: i32) -> HeartRateAtTime {
+        HeartRateAtTime {
+            heart_rate: 100 + time / 450,
+            time: f64::from(time),
+        }
+    }
+
+    #[test]
+    fn test_averages_are_provisional_until_the_segments_fill() {
+        let mut live = LiveDrift::new(&DriftSettings::default(), None).unwrap();
+        for time in 0..1800 {
+            live.push(rising_from(time)).unwrap();
+        }
+
+        let update = live.update().unwrap();
+
+        assert_eq!(Some(1), update.segment);
+        assert_eq!(900, update.segments[0].samples);
+        assert_eq!(None, update.segments[1].average_heart_rate);
+        assert_eq!(None, update.drift);
+        assert!(update.projected_drift.unwrap() > 0.0);
+        assert!(!live.is_over());
+    }
+
+    #[test]
+    fn test_final_update_matches_the_report() {
+        let mut live = LiveDrift::new(&DriftSettings::default(), None).unwrap();
+        for time in 0..4501 {
+            live.push(rising_from(time)).unwrap();
+        }
+
+        let update = live.update().unwrap();
+        assert!(live.is_over());
+        assert_eq!(None, update.segment);
+        assert_eq!(Some(live.finish().unwrap().drift), update.drift);
+    }
+
+    #[test]
+    fn test_split_protocol_needs_a_duration() {
+        let settings = DriftSettings::from(DriftProtocol::training_peaks());
+
+        assert_eq!(
+            Some(HeartRateDriftError::UnknownDuration),
+            LiveDrift::new(&settings, None).err()
+        );
+        let live = LiveDrift::new(&settings, Some(3600)).unwrap();
+        assert_eq!(1800, live.accumulator.segments()[1].start);
+    }
+
+    #[test]
+    fn test_samples_out_of_order_are_refused() {
+        let mut live = LiveDrift::new(&DriftSettings::default(), None).unwrap();
+        live.push(rising_from(10)).unwrap();
+
+        assert_eq!(
+            Err(HeartRateDriftError::LateSample {
+                time: 5.0,
+                latest: 10.0
+            }),
+            live.push(rising_from(5))
+        );
+        assert!(live.push(rising_from(10)).is_err());
+        assert!(matches!(
+            live.push(HeartRateAtTime {
+                heart_rate: 100,
+                time: f64::NAN
+            }),
+            Err(HeartRateDriftError::InvalidTime { .. })
+        ));
+        assert_eq!(10.0, live.update().unwrap().time);
+    }
+}
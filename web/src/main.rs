@@ -1,18 +1,115 @@
-mod heart_rate_drift;
-
-use anyhow::Result;
-use clap::Parser;
-use heart_rate_drift::{combine_hr_with_time, HeartRateDrift};
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use heart_rate_drift_calculator::analysis::{AnalysisOptions, Streams};
+use heart_rate_drift_calculator::averaging::Averaging;
+use heart_rate_drift_calculator::bootstrap::BootstrapOptions;
+use heart_rate_drift_calculator::fit::{is_fit, read_fit};
+use heart_rate_drift_calculator::heart_rate_drift::{
+    BeatsPerMinute, DriftSettings, HeartRateAtTime, Seconds,
+};
+use heart_rate_drift_calculator::laps::Lap;
+use heart_rate_drift_calculator::live::LiveDrift;
+use heart_rate_drift_calculator::protocol::ProtocolOptions;
+use heart_rate_drift_calculator::quality::QualityThresholds;
+use heart_rate_drift_calculator::rolling::{rolling_drift, RollingFormat, RollingOptions};
+use heart_rate_drift_calculator::rr::parse_rr_intervals;
+use heart_rate_drift_calculator::trend::Fit;
 use serde::Deserialize;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufRead, BufReader};
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// How often a followed file is checked for new samples
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
 
+/// Calculates heart rate drift from a Strava activity to find the aerobic threshold
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// Filepath. Relative or absolute should work
-    filepath: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Filepath of the Strava streams as JSON, or of a FIT file. Relative or absolute should work
+    #[arg(required = true)]
+    filepath: Option<String>,
+
+    /// Laps saved from Strava's laps endpoint. The test segments are then laps rather than
+    /// fixed offsets. Laps in the activity file itself are used when this isn't given
+    #[arg(long)]
+    laps: Option<String>,
+
+    /// Beat-to-beat RR intervals for the activity, as text with one interval in milliseconds per
+    /// line, or `time,interval` columns. Used to find where DFA α1 crosses 0.75, to check the
+    /// aerobic threshold from drift against. Replaces the HRV messages of a FIT file
+    #[arg(long)]
+    rr: Option<String>,
+
+    #[command(flatten)]
+    analysis: AnalysisOptions,
+
+    /// Print drift over time across the whole activity instead of the report
+    #[arg(long, value_enum)]
+    rolling: Option<RollingFormat>,
+
+    #[command(flatten)]
+    rolling_options: RollingOptions,
+
+    /// Print the full report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Follow drift during a workout, reading one `time,heart rate` sample per line and
+    /// printing updates as they come in
+    Live(LiveArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct LiveArgs {
+    /// File the samples are written to. Reads stdin when not given
+    filepath: Option<String>,
+
+    /// Keep reading the file as it grows, like tail -f, until the test is over
+    #[arg(long, requires = "filepath")]
+    follow: bool,
+
+    #[command(flatten)]
+    protocol: ProtocolOptions,
+
+    /// Planned length of the workout, in minutes. Needed for protocols that split the whole
+    /// workout, since the segments are laid out before it ends
+    #[arg(long)]
+    duration: Option<i32>,
+
+    /// How samples are averaged within a segment
+    #[arg(long, value_enum, default_value_t = Averaging::default())]
+    averaging: Averaging,
+
+    #[command(flatten)]
+    quality: QualityThresholds,
+
+    /// How the line is fitted for the projected drift
+    #[arg(long, value_enum, default_value_t = Fit::default())]
+    fit: Fit,
+
+    /// Seconds of workout between updates
+    #[arg(long, default_value_t = 60)]
+    every: i32,
+
+    /// Print each update, and the report at the end, as a line of JSON
+    #[arg(long)]
+    json: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -22,26 +119,238 @@ struct HeartRates {
 
 #[derive(Debug, Deserialize)]
 struct Times {
-    data: Vec<i32>,
+    data: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Velocities {
+    data: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Distances {
+    data: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Watts {
+    data: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Cadences {
+    data: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Altitudes {
+    data: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Temperatures {
+    data: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Moving {
+    data: Vec<bool>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Activity {
     heartrate: HeartRates,
     time: Times,
+    velocity_smooth: Option<Velocities>,
+    distance: Option<Distances>,
+    watts: Option<Watts>,
+    cadence: Option<Cadences>,
+    moving: Option<Moving>,
+    altitude: Option<Altitudes>,
+    temp: Option<Temperatures>,
+    laps: Option<Vec<Lap>>,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let path = Path::new(args.filepath.as_str());
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+impl Activity {
+    /// Prefers Strava's smoothed velocity over speed worked out from distance
+    fn into_streams(self) -> Streams {
+        Streams {
+            heart_rates: self.heartrate.data,
+            times: self.time.data,
+            speeds: self.velocity_smooth.map(|velocities| velocities.data),
+            distances: self.distance.map(|distances| distances.data),
+            watts: self.watts.map(|watts| watts.data),
+            cadence: self.cadence.map(|cadence| cadence.data),
+            moving: self.moving.map(|moving| moving.data),
+            altitudes: self.altitude.map(|altitudes| altitudes.data),
+            temperatures: self.temp.map(|temperatures| temperatures.data),
+            laps: self.laps,
+            beats: None,
+        }
+    }
+}
 
-    let u: Activity = serde_json::from_reader(reader)?;
+/// Reads samples as they arrive and prints where the test stands
+fn live(args: &LiveArgs) -> Result<()> {
+    if args.protocol.detect_window {
+        return Err(anyhow!(
+            "The test can't be looked for while the workout is still going"
+        ));
+    }
+    let settings = DriftSettings {
+        protocol: args.protocol.protocol(),
+        averaging: args.averaging,
+        quality: args.quality,
+        fit: args.fit,
+        bootstrap: BootstrapOptions::default(),
+    };
+    let mut live = LiveDrift::new(&settings, args.duration.map(|minutes| minutes * 60))?;
+    let mut reader: Box<dyn BufRead> = match &args.filepath {
+        Some(path) => Box::new(BufReader::new(File::open(Path::new(path))?)),
+        None => Box::new(io::stdin().lock()),
+    };
 
-    let combined = combine_hr_with_time(u.heartrate.data.as_slice(), u.time.data.as_slice());
+    let mut line = String::new();
+    let mut line_number = 0;
+    let mut next_update = f64::NEG_INFINITY;
+    while !live.is_over() {
+        let read = reader.read_line(&mut line)?;
+        if read == 0 || !line.ends_with('\n') {
+            // Caught up with the writer, who may be halfway through a line
+            if args.follow {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            if line.is_empty() {
+                break;
+            }
+        }
+        line_number += 1;
+        let sample = parse_sample(&line);
+        line.clear();
+        let pushed = match sample {
+            Ok(Some(sample)) => live.push(sample).map_err(anyhow::Error::from),
+            Ok(None) => continue,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = pushed {
+            eprintln!("Skipping line {line_number}: {error}");
+            continue;
+        }
+
+        if let Some(update) = live.update().filter(|update| update.time >= next_update) {
+            next_update = update.time + f64::from(args.every);
+            if args.json {
+                println!("{}", serde_json::to_string(&update)?);
+            } else {
+                println!("{update}");
+            }
+        }
+    }
+
+    let report = live.finish()?;
+    if args.json {
+        println!("{}", serde_json::to_string(&report)?);
+    } else {
+        println!("{report}");
+    }
+    Ok(())
+}
+
+/// A `time,heart rate` line, separated by a comma or whitespace. None for blank lines and
+/// anything without a time first, like a header.
+fn parse_sample(line: &str) -> Result<Option<HeartRateAtTime>> {
+    let mut fields = line
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|field| !field.is_empty());
+    let Some(Ok(time)) = fields.next().map(str::parse::<Seconds>) else {
+        return Ok(None);
+    };
+    let heart_rate = fields
+        .next()
+        .ok_or_else(|| anyhow!("No heart rate after the time"))?;
+    let heart_rate = heart_rate
+        .parse::<BeatsPerMinute>()
+        .map_err(|_| anyhow!("{heart_rate} isn't a heart rate"))?;
+    Ok(Some(HeartRateAtTime { heart_rate, time }))
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if let Some(Command::Live(live_args)) = &args.command {
+        return live(live_args);
+    }
+    let filepath = args
+        .filepath
+        .as_deref()
+        .ok_or_else(|| anyhow!("No activity file given"))?;
+    let bytes = std::fs::read(Path::new(filepath))?;
+    let mut streams = if is_fit(&bytes) {
+        read_fit(&bytes)?
+    } else {
+        serde_json::from_slice::<Activity>(&bytes)?.into_streams()
+    };
+    if let Some(laps) = &args.laps {
+        let reader = BufReader::new(File::open(Path::new(laps))?);
+        streams.laps = Some(serde_json::from_reader(reader)?);
+    }
+    if let Some(rr) = &args.rr {
+        streams.beats = Some(parse_rr_intervals(&std::fs::read_to_string(Path::new(
+            rr,
+        ))?)?);
+    }
+    let options = &args.analysis;
+    let prepared = streams.prepare(options)?;
+    if !prepared.repairs.is_empty() && !args.json {
+        println!("{}", prepared.repairs);
+    }
+    if !options.filters.is_empty() && !args.json {
+        println!("{}", prepared.filters);
+    }
+    if let Some(format) = args.rolling {
+        let rolling = rolling_drift(&prepared.samples, &args.rolling_options, options.averaging);
+        match format {
+            RollingFormat::Csv => print!("{}", rolling.to_csv()),
+            RollingFormat::Json => println!("{}", serde_json::to_string_pretty(&rolling)?),
+        }
+        return Ok(());
+    }
+    let report = prepared.report(options)?;
 
-    print!("Heart rate drift is {}", combined.heart_rate_drift()?);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        if let Some(decoupling) = report.decoupling {
+            println!(
+                "{} is {:.2}%",
+                options.decoupling.mode.label(),
+                decoupling.decoupling
+            );
+        }
+        if let Some(decoupling) = report.grade_adjusted_decoupling {
+            println!(
+                "Grade-adjusted {} is {:.2}%",
+                options.decoupling.mode.label(),
+                decoupling.decoupling
+            );
+        }
+        println!("{report}");
+        if let Some(output) = report
+            .interpretation
+            .next_test
+            .and_then(|next_test| next_test.output)
+        {
+            let on_the_flat = if report.grade_adjusted_decoupling.is_some() {
+                " on the flat"
+            } else {
+                ""
+            };
+            println!(
+                "{}{on_the_flat}",
+                options.decoupling.mode.describe_output(output)
+            );
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,231 @@
+use crate::heart_rate_drift::{whole_seconds, HeartRateDriftError, Seconds};
+use crate::quality::Span;
+use crate::report::minutes_and_seconds;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Whether to measure the test on moving time, and what speed counts as stopped
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MovingOptions {
+    /// Leave out stops and lay the segments over moving time rather than elapsed time. Uses the
+    /// moving stream, or the speed when the activity doesn't have one
+    #[arg(long)]
+    pub moving_time: bool,
+    /// Metres per second below which you count as stopped, when there's no moving stream
+    #[arg(long, default_value_t = MovingOptions::default().stop_speed)]
+    pub stop_speed: f64,
+}
+
+impl Default for MovingOptions {
+    fn default() -> Self {
+        Self {
+            moving_time: false,
+            stop_speed: 0.5,
+        }
+    }
+}
+
+/// Stops left out of the test, in elapsed time
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stops {
+    pub spans: Vec<Span>,
+    /// Seconds left out in total
+    #[serde(serialize_with = "whole_seconds")]
+    pub duration: Seconds,
+}
+
+/// The activity with the stops taken out
+#[derive(Clone, Debug, PartialEq)]
+pub struct MovingTime {
+    /// One entry per sample, true when moving
+    pub keep: Vec<bool>,
+    /// Moving time of each sample that was kept
+    pub times: Vec<Seconds>,
+    pub stops: Stops,
+}
+
+impl MovingTime {
+    /// The values recorded while moving, so the other streams line up with `times`
+    pub fn retain<T: Copy>(&self, values: &[T]) -> Vec<T> {
+        values
+            .iter()
+            .zip(&self.keep)
+            .filter_map(|(value, &keep)| keep.then_some(*value))
+            .collect()
+    }
+
+    /// How many samples before `index` were kept, which is where the sample at `index` (or the
+    /// next moving one) ends up in the retained streams
+    pub fn kept_before(&self, index: usize) -> usize {
+        self.keep[..index.min(self.keep.len())]
+            .iter()
+            .filter(|&&keep| keep)
+            .count()
+    }
+}
+
+/// Strava's moving stream, rebuilt from speed for files that don't have it
+pub fn moving_from_speed(speeds: &[f64], stop_speed: f64) -> Vec<bool> {
+    speeds.iter().map(|&speed| speed >= stop_speed).collect()
+}
+
+/// Turns elapsed `times` into moving time. Each moving sample adds the time since the sample
+/// before it, so a stop is everything from the last moving sample to the last stopped one.
+/// `moving` has to have a value for every time.
+pub fn moving_time(times: &[Seconds], moving: &[bool]) -> Result<MovingTime, HeartRateDriftError> {
+    if moving.len() != times.len() {
+        return Err(HeartRateDriftError::LengthMismatch {
+            stream: "moving",
+            expected: times.len(),
+            found: moving.len(),
+        });
+    }
+    let mut keep = Vec::with_capacity(times.len());
+    let mut moving_times = Vec::new();
+    let mut spans: Vec<Span> = Vec::new();
+    let mut clock = times.first().copied().unwrap_or_default();
+
+    for (index, (&time, &is_moving)) in times.iter().zip(moving).enumerate() {
+        let previous = index.checked_sub(1).map(|previous| times[previous]);
+        if is_moving {
+            clock += previous.map_or(0.0, |previous| time - previous);
+            moving_times.push(clock);
+        } else {
+            let stopped_before = index > 0 && !moving[index - 1];
+            match spans.last_mut() {
+                Some(span) if stopped_before => span.end = time,
+                _ => spans.push(Span {
+                    start: previous.unwrap_or(time),
+                    end: time,
+                }),
+            }
+        }
+        keep.push(is_moving);
+    }
+
+    Ok(MovingTime {
+        keep,
+        times: moving_times,
+        stops: Stops {
+            duration: spans.iter().map(Span::duration).sum(),
+            spans,
+        },
+    })
+}
+
+impl Stops {
+    /// Where `time`, in elapsed time, falls in moving time. None during a stop.
+    pub fn moving_time(&self, time: Seconds) -> Option<Seconds> {
+        if self
+            .spans
+            .iter()
+            .any(|span| time > span.start && time <= span.end)
+        {
+            return None;
+        }
+        let stopped: Seconds = self
+            .spans
+            .iter()
+            .filter(|span| span.end <= time)
+            .map(Span::duration)
+            .sum();
+        Some(time - stopped)
+    }
+}
+
+impl fmt::Display for Stops {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Left out {} stops, {} in total",
+            self.spans.len(),
+            minutes_and_seconds(self.duration.round() as i32)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moving_the_whole_time_keeps_elapsed_time() {
+        let moving_time = moving_time(&[0.0, 1.0, 2.0, 5.0], &[true; 4]).unwrap();
+
+        assert_eq!(vec![0.0, 1.0, 2.0, 5.0], moving_time.times);
+        assert!(moving_time.stops.spans.is_empty());
+    }
+
+    #[test]
+    fn test_stops_are_taken_out_of_the_time_axis() {
+        let times = [0.0, 10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0];
+        let moving = [true, true, false, false, true, true, false, true];
+
+        let moving_time = moving_time(&times, &moving).unwrap();
+
+        assert_eq!(vec![0.0, 10.0, 20.0, 30.0, 40.0], moving_time.times);
+        assert_eq!(
+            vec![
+                Span {
+                    start: 10.0,
+                    end: 30.0
+                },
+                Span {
+                    start: 50.0,
+                    end: 60.0
+                }
+            ],
+            moving_time.stops.spans
+        );
+        assert_eq!(30.0, moving_time.stops.duration);
+        assert_eq!(
+            vec![140, 141, 144, 145, 147],
+            moving_time.retain(&[140, 141, 120, 118, 144, 145, 130, 147])
+        );
+        assert_eq!(2, moving_time.kept_before(3));
+        assert_eq!(5, moving_time.kept_before(100));
+        assert_eq!(Some(5.0), moving_time.stops.moving_time(5.0));
+        assert_eq!(None, moving_time.stops.moving_time(25.0));
+        assert_eq!(Some(25.0), moving_time.stops.moving_time(45.0));
+    }
+
+    #[test]
+    fn test_moving_stream_has_to_line_up_with_the_times() {
+        assert_eq!(
+            Err(HeartRateDriftError::LengthMismatch {
+                stream: "moving",
+                expected: 3,
+                found: 2
+            }),
+            moving_time(&[0.0, 1.0, 2.0], &[true, false])
+        );
+    }
+
+    #[test]
+    fn test_whole_seconds_serialize_as_integers() {
+        let stops = Stops {
+            spans: vec![Span {
+                start: 10.0,
+                end: 30.5,
+            }],
+            duration: 20.5,
+        };
+
+        let json = serde_json::to_string(&stops).unwrap();
+
+        assert_eq!(
+            r#"{"spans":[{"start":10,"end":30.5}],"duration":20.5}"#,
+            json
+        );
+    }
+
+    #[test]
+    fn test_slow_enough_counts_as_stopped() {
+        assert_eq!(
+            vec![true, false, false, true],
+            moving_from_speed(&[2.8, 0.4, 0.0, 0.5], 0.5)
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use crate::heart_rate_drift::Seconds;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_MINUTE: i32 = 60;
+
+/// How long each test segment is
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SegmentLength {
+    /// Every segment is this many seconds long
+    Fixed(i32),
+    /// Whatever is left after the warm-up (and cool-down) is split evenly between the segments
+    Split,
+    /// Each segment is a lap the athlete marked on their watch
+    Laps(Vec<Segment>),
+}
+
+/// A window of the activity, from `start` up to but not including `end`, in seconds
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Segment {
+    pub start: i32,
+    pub end: i32,
+}
+
+impl Segment {
+    pub fn contains(&self, time: Seconds) -> bool {
+        time >= f64::from(self.start) && time < f64::from(self.end)
+    }
+}
+
+/// Describes how a drift test is laid out: a warm-up that is ignored, followed by a number of
+/// segments. Drift is the rise in heart rate from the first segment to the last.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriftProtocol {
+    /// Seconds into the activity the test starts, for tests inside a longer activity
+    pub start: i32,
+    /// Seconds at the start of the test that are ignored
+    pub warm_up: i32,
+    pub segment_length: SegmentLength,
+    pub segments: usize,
+    /// Seconds at the end of the activity that are ignored
+    pub cool_down: i32,
+}
+
+impl DriftProtocol {
+    /// 15 minute warm-up followed by two 30 minute segments.
+    /// See https://uphillathlete.com/aerobic-training/heart-rate-drift/
+    pub fn uphill_athlete() -> Self {
+        Self {
+            start: 0,
+            warm_up: 15 * SECONDS_PER_MINUTE,
+            segment_length: SegmentLength::Fixed(30 * SECONDS_PER_MINUTE),
+            segments: 2,
+            cool_down: 0,
+        }
+    }
+
+    /// Training Peaks' decoupling compares the first half of the whole workout to the second
+    pub fn training_peaks() -> Self {
+        Self {
+            start: 0,
+            warm_up: 0,
+            segment_length: SegmentLength::Split,
+            segments: 2,
+            cool_down: 0,
+        }
+    }
+
+    /// 10 minute warm-up, then 20 minutes steady compared half against half
+    pub fn friel() -> Self {
+        Self {
+            start: 0,
+            warm_up: 10 * SECONDS_PER_MINUTE,
+            segment_length: SegmentLength::Fixed(10 * SECONDS_PER_MINUTE),
+            segments: 2,
+            cool_down: 0,
+        }
+    }
+
+    /// The segments are the given laps, in activity time. Everything before the first lap is
+    /// the warm-up.
+    pub fn from_laps(laps: Vec<Segment>) -> Self {
+        Self {
+            start: 0,
+            warm_up: laps.first().map_or(0, |lap| lap.start),
+            segments: laps.len(),
+            segment_length: SegmentLength::Laps(laps),
+            cool_down: 0,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let positive_length = match &self.segment_length {
+            SegmentLength::Fixed(length) => *length > 0,
+            SegmentLength::Split => true,
+            SegmentLength::Laps(laps) => {
+                laps.len() == self.segments && laps.iter().all(|lap| lap.end > lap.start)
+            }
+        };
+        self.segments >= 2
+            && positive_length
+            && self.start >= 0
+            && self.warm_up >= 0
+            && self.cool_down >= 0
+    }
+
+    /// Seconds from the start of the activity to the end of the cool-down, for protocols that
+    /// can be laid out without knowing how long the activity turns out to be
+    pub fn planned_length(&self) -> Option<i32> {
+        let test_end = match &self.segment_length {
+            SegmentLength::Fixed(length) => {
+                self.start + self.warm_up + self.segments as i32 * length
+            }
+            SegmentLength::Split => return None,
+            SegmentLength::Laps(laps) => laps.last()?.end,
+        };
+        Some(test_end + self.cool_down)
+    }
+
+    /// Lays the protocol over an activity whose last sample is at `last_time`.
+    pub fn windows(&self, last_time: Seconds) -> Vec<Segment> {
+        // Segments are half-open, so one past the last whole second keeps the last sample in
+        let end = last_time.floor() as i32 - self.cool_down + 1;
+        let length = match &self.segment_length {
+            SegmentLength::Fixed(length) => *length,
+            SegmentLength::Split => (end - self.start - self.warm_up) / self.segments as i32,
+            SegmentLength::Laps(laps) => return laps.clone(),
+        };
+
+        (0..self.segments)
+            .map(|index| {
+                let start = self.start + self.warm_up + index as i32 * length;
+                let segment_end = match self.segment_length {
+                    SegmentLength::Split if index + 1 == self.segments => end,
+                    _ => (start + length).min(end),
+                };
+                Segment {
+                    start,
+                    end: segment_end,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for DriftProtocol {
+    fn default() -> Self {
+        Self::uphill_athlete()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    #[default]
+    UphillAthlete,
+    TrainingPeaks,
+    Friel,
+}
+
+impl From<Preset> for DriftProtocol {
+    fn from(preset: Preset) -> Self {
+        match preset {
+            Preset::UphillAthlete => Self::uphill_athlete(),
+            Preset::TrainingPeaks => Self::training_peaks(),
+            Preset::Friel => Self::friel(),
+        }
+    }
+}
+
+/// A preset protocol plus any overrides of its start, warm-up, segments and cool-down, or a
+/// request to find the test in the activity. Lengths are in minutes, since that's how people
+/// describe their tests.
+#[derive(clap::Args, Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ProtocolOptions {
+    /// Test protocol to lay over the activity
+    #[arg(long = "protocol", value_enum, default_value_t = ProtocolOptions::default().preset)]
+    #[serde(rename = "protocol")]
+    pub preset: Preset,
+    /// Minutes into the activity the test starts
+    #[arg(long)]
+    pub start: Option<i32>,
+    /// Override the warm-up length, in minutes
+    #[arg(long)]
+    pub warm_up: Option<i32>,
+    /// Override the segment length, in minutes
+    #[arg(long)]
+    pub segment_length: Option<i32>,
+    /// Override the number of segments
+    #[arg(long)]
+    pub segments: Option<usize>,
+    /// Ignore this many minutes at the end of the activity
+    #[arg(long)]
+    pub cool_down: Option<i32>,
+    /// Look for the test inside a longer activity instead of starting it at the beginning
+    #[arg(long)]
+    pub detect_window: bool,
+}
+
+impl ProtocolOptions {
+    pub fn protocol(&self) -> DriftProtocol {
+        let preset = DriftProtocol::from(self.preset);
+        DriftProtocol {
+            start: self
+                .start
+                .map_or(preset.start, |minutes| minutes * SECONDS_PER_MINUTE),
+            warm_up: self
+                .warm_up
+                .map_or(preset.warm_up, |minutes| minutes * SECONDS_PER_MINUTE),
+            segment_length: self
+                .segment_length
+                .map_or(preset.segment_length.clone(), |minutes| {
+                    SegmentLength::Fixed(minutes * SECONDS_PER_MINUTE)
+                }),
+            segments: self.segments.unwrap_or(preset.segments),
+            cool_down: self
+                .cool_down
+                .map_or(preset.cool_down, |minutes| minutes * SECONDS_PER_MINUTE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uphill_athlete_windows_ignore_activity_length() {
+        let windows = DriftProtocol::uphill_athlete().windows(10_000.0);
+
+        assert_eq!(
+            vec![
+                Segment {
+                    start: 900,
+                    end: 2700
+                },
+                Segment {
+                    start: 2700,
+                    end: 4500
+                }
+            ],
+            windows
+        );
+    }
+
+    #[test]
+    fn test_planned_length_is_known_unless_the_activity_is_split() {
+        let protocol = DriftProtocol {
+            cool_down: 300,
+            ..DriftProtocol::uphill_athlete()
+        };
+
+        assert_eq!(Some(4800), protocol.planned_length());
+        assert_eq!(None, DriftProtocol::training_peaks().planned_length());
+    }
+
+    #[test]
+    fn test_training_peaks_splits_the_whole_workout_in_half() {
+        let windows = DriftProtocol::training_peaks().windows(3599.0);
+
+        assert_eq!(
+            vec![
+                Segment {
+                    start: 0,
+                    end: 1800
+                },
+                Segment {
+                    start: 1800,
+                    end: 3600
+                }
+            ],
+            windows
+        );
+    }
+
+    #[test]
+    fn test_cool_down_trims_the_end_of_the_last_segment() {
+        let protocol = DriftProtocol {
+            cool_down: 600,
+            ..DriftProtocol::uphill_athlete()
+        };
+
+        let windows = protocol.windows(4000.0);
+
+        assert_eq!(3401, windows[1].end);
+    }
+
+    #[test]
+    fn test_start_moves_every_window() {
+        let protocol = DriftProtocol {
+            start: 1200,
+            ..DriftProtocol::friel()
+        };
+
+        assert_eq!(
+            vec![
+                Segment {
+                    start: 1800,
+                    end: 2400
+                },
+                Segment {
+                    start: 2400,
+                    end: 3000
+                }
+            ],
+            protocol.windows(10_000.0)
+        );
+    }
+
+    #[test]
+    fn test_lap_windows_are_used_as_is() {
+        let laps = vec![
+            Segment {
+                start: 600,
+                end: 2400,
+            },
+            Segment {
+                start: 2400,
+                end: 4300,
+            },
+        ];
+
+        let protocol = DriftProtocol::from_laps(laps.clone());
+
+        assert!(protocol.is_valid());
+        assert_eq!(600, protocol.warm_up);
+        assert_eq!(laps, protocol.windows(10_000.0));
+    }
+
+    #[test]
+    fn test_options_override_the_preset_in_minutes() {
+        let options = ProtocolOptions {
+            preset: Preset::UphillAthlete,
+            warm_up: Some(20),
+            segment_length: Some(45),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            DriftProtocol {
+                start: 0,
+                warm_up: 1200,
+                segment_length: SegmentLength::Fixed(2700),
+                segments: 2,
+                cool_down: 0,
+            },
+            options.protocol()
+        );
+    }
+
+    #[test]
+    fn test_options_deserialize_from_preset_name() {
+        let options: ProtocolOptions =
+            serde_json::from_str(r#"{"protocol": "training-peaks", "coolDown": 5}"#).unwrap();
+
+        assert_eq!(Preset::TrainingPeaks, options.preset);
+        assert_eq!(300, options.protocol().cool_down);
+    }
+}
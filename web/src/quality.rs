@@ -0,0 +1,249 @@
+use crate::heart_rate_drift::{whole_seconds, Seconds};
+use crate::protocol::Segment;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// When gaps and flatlines are worth mentioning, and how little coverage is too little
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct QualityThresholds {
+    /// Seconds between samples before it counts as a dropout
+    #[arg(long, default_value_t = QualityThresholds::default().max_gap)]
+    pub max_gap: i32,
+    /// Seconds of the exact same heart rate before the sensor is considered stuck
+    #[arg(long, default_value_t = QualityThresholds::default().flatline)]
+    pub flatline: i32,
+    /// Refuse to calculate drift when a compared segment has less than this percentage covered
+    #[arg(long)]
+    pub min_coverage: Option<f64>,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            max_gap: 10,
+            flatline: 300,
+            min_coverage: None,
+        }
+    }
+}
+
+/// A stretch of time, in seconds, from `start` to `end`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Span {
+    #[serde(serialize_with = "whole_seconds")]
+    pub start: Seconds,
+    #[serde(serialize_with = "whole_seconds")]
+    pub end: Seconds,
+}
+
+impl Span {
+    pub fn duration(&self) -> Seconds {
+        self.end - self.start
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentQuality {
+    pub segment: Segment,
+    /// Percentage of the segment that has samples no more than `max_gap` apart
+    pub coverage: f64,
+    pub gaps: Vec<Span>,
+    pub zero_readings: usize,
+    pub flatlines: Vec<Span>,
+}
+
+/// How trustworthy the heart rate was in each segment of the protocol
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DataQuality {
+    pub segments: Vec<SegmentQuality>,
+}
+
+/// Checks the `(time, heart rate)` samples that fall in a segment, taking one at a time so the
+/// samples don't have to be kept
+#[derive(Clone, Debug, PartialEq)]
+pub struct QualityCheck {
+    segment: Segment,
+    thresholds: QualityThresholds,
+    /// Time of the last sample, or the start of the segment before there is one
+    previous: Seconds,
+    gaps: Vec<Span>,
+    zero_readings: usize,
+    flatlines: Vec<Span>,
+    /// The run of identical heart rates the last sample belongs to
+    run: Option<(Span, f64)>,
+}
+
+impl QualityCheck {
+    pub fn new(segment: &Segment, thresholds: &QualityThresholds) -> Self {
+        Self {
+            segment: *segment,
+            thresholds: *thresholds,
+            previous: f64::from(segment.start),
+            gaps: Vec::new(),
+            zero_readings: 0,
+            flatlines: Vec::new(),
+            run: None,
+        }
+    }
+
+    /// Adds a sample in the segment, which must not be earlier than the one before it
+    pub fn push(&mut self, time: Seconds, rate: f64) {
+        self.gap_until(time);
+        if rate <= 0.0 {
+            self.zero_readings += 1;
+        }
+        match &mut self.run {
+            Some((span, value)) if *value == rate => span.end = time,
+            _ => {
+                self.end_run();
+                self.run = Some((
+                    Span {
+                        start: time,
+                        end: time,
+                    },
+                    rate,
+                ));
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> SegmentQuality {
+        self.gap_until(f64::from(self.segment.end));
+        self.end_run();
+        let missing: Seconds = self.gaps.iter().map(Span::duration).sum();
+        let length = f64::from(self.segment.end - self.segment.start);
+        let coverage = if length > 0.0 {
+            100.0 * (length - missing) / length
+        } else {
+            0.0
+        };
+
+        SegmentQuality {
+            segment: self.segment,
+            coverage,
+            gaps: self.gaps,
+            zero_readings: self.zero_readings,
+            flatlines: self.flatlines,
+        }
+    }
+
+    /// Stretches longer than `max_gap` without a sample count as gaps, including at either end
+    /// of the segment
+    fn gap_until(&mut self, time: Seconds) {
+        let span = Span {
+            start: self.previous,
+            end: time,
+        };
+        if span.duration() > f64::from(self.thresholds.max_gap) {
+            self.gaps.push(span);
+        }
+        self.previous = time;
+    }
+
+    fn end_run(&mut self) {
+        if let Some((span, _)) = self.run.take() {
+            if span.duration() >= f64::from(self.thresholds.flatline) {
+                self.flatlines.push(span);
+            }
+        }
+    }
+}
+
+impl fmt::Display for SegmentQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let longest_gap = self.gaps.iter().map(Span::duration).fold(0.0, f64::max);
+        write!(
+            f,
+            "{:.1}% coverage, {} gaps (longest {}s), {} zero readings, {} flatlines",
+            self.coverage,
+            self.gaps.len(),
+            longest_gap,
+            self.zero_readings,
+            self.flatlines.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heart_rate_drift::values_every_second;
+
+    const SEGMENT: Segment = Segment { start: 0, end: 600 };
+
+    fn segment_quality(samples: &[(Seconds, f64)]) -> SegmentQuality {
+        let mut check = QualityCheck::new(&SEGMENT, &QualityThresholds::default());
+        for &(time, rate) in samples {
+            check.push(time, rate);
+        }
+        check.finish()
+    }
+
+    #[test]
+    fn test_samples_every_second_are_fully_covered() {
+        let samples: Vec<(Seconds, f64)> = (0..600)
+            .map(|time| (f64::from(time), f64::from(time)))
+            .collect();
+
+        let quality = segment_quality(&samples);
+
+        assert_eq!(100.0, quality.coverage);
+        assert!(quality.gaps.is_empty());
+        assert!(quality.flatlines.is_empty());
+    }
+
+    #[test]
+    fn test_four_minute_dropout_is_a_gap() {
+        let mut samples: Vec<(Seconds, f64)> = (0..300)
+            .map(|time| (f64::from(time), f64::from(time)))
+            .collect();
+        samples.extend((540..600).map(|time| (f64::from(time), f64::from(time))));
+
+        let quality = segment_quality(&samples);
+
+        assert_eq!(
+            vec![Span {
+                start: 299.0,
+                end: 540.0
+            }],
+            quality.gaps
+        );
+        assert_eq!(100.0 * 359.0 / 600.0, quality.coverage);
+    }
+
+    #[test]
+    fn test_empty_segment_has_no_coverage() {
+        let quality = segment_quality(&[]);
+
+        assert_eq!(0.0, quality.coverage);
+    }
+
+    #[test]
+    fn test_zero_readings_are_counted() {
+        let mut samples = values_every_second(0, 300, |_| 140.0);
+        samples.extend(values_every_second(300, 310, |_| 0.0));
+        samples.extend(values_every_second(310, 600, |_| 141.0));
+
+        let quality = segment_quality(&samples);
+
+        assert_eq!(10, quality.zero_readings);
+    }
+
+    #[test]
+    fn test_stuck_heart_rate_is_a_flatline() {
+        let mut samples = values_every_second(0, 400, |_| 140.0);
+        samples.extend(values_every_second(400, 600, |_| 141.0));
+
+        let quality = segment_quality(&samples);
+
+        assert_eq!(
+            vec![Span {
+                start: 0.0,
+                end: 399.0
+            }],
+            quality.flatlines
+        );
+    }
+}
@@ -0,0 +1,386 @@
+use crate::averaging::Averaging;
+use crate::bootstrap::ConfidenceInterval;
+use crate::decoupling::DecouplingResult;
+use crate::detection::DetectedWindow;
+use crate::dfa::DfaAlpha1;
+use crate::heart_rate_drift::Seconds;
+use crate::hrv::SegmentHrv;
+use crate::interpretation::Interpretation;
+use crate::lag::CardiacLag;
+use crate::moving::Stops;
+use crate::protocol::{DriftProtocol, Segment, SegmentLength};
+use crate::quality::DataQuality;
+use crate::rr::{Beat, CleanBeats};
+use crate::temperature::{TemperatureContext, TemperatureOptions};
+use crate::trend::DriftTrend;
+use serde::Serialize;
+use std::fmt;
+
+/// Above this the test was run too hard to find aerobic threshold
+pub(crate) const TOO_FAST_DRIFT: f64 = 5.0;
+/// Below this the test was run too easy to find aerobic threshold
+pub(crate) const TOO_SLOW_DRIFT: f64 = 3.5;
+
+/// What a drift percentage says about the pace the test was run at
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Verdict {
+    TooFast,
+    InRange,
+    TooSlow,
+}
+
+impl Verdict {
+    pub fn from_drift(drift: f64) -> Self {
+        if drift > TOO_FAST_DRIFT {
+            Verdict::TooFast
+        } else if drift < TOO_SLOW_DRIFT {
+            Verdict::TooSlow
+        } else {
+            Verdict::InRange
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::TooFast => write!(
+                f,
+                "more than 5%, so you were running too fast to determine your aerobic threshold"
+            ),
+            Verdict::InRange => write!(
+                f,
+                "between 3.5% and 5%, so your aerobic threshold is right around your heart rate at the beginning of the test"
+            ),
+            Verdict::TooSlow => write!(f, "less than 3.5%, so you were probably running too slow"),
+        }
+    }
+}
+
+/// Heart rate over one segment of the protocol. The statistics are missing when the segment
+/// has no samples, which can happen to the middle segments of a longer protocol.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentSummary {
+    pub start: i32,
+    pub end: i32,
+    pub samples: usize,
+    pub average_heart_rate: Option<f64>,
+    pub min_heart_rate: Option<f64>,
+    pub max_heart_rate: Option<f64>,
+}
+
+/// Everything worth showing about a drift calculation
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriftReport {
+    pub protocol: DriftProtocol,
+    /// Where the test was found, when it was looked for inside a longer activity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detected_window: Option<DetectedWindow>,
+    /// Stops left out when the segments were laid over moving time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stops: Option<Stops>,
+    pub segments: Vec<SegmentSummary>,
+    /// Percentage rise in average heart rate from the first segment to the last
+    pub drift: f64,
+    pub verdict: Verdict,
+    /// Bootstrapped confidence interval for `drift`, when it was asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<ConfidenceInterval>,
+    /// Drift from a line fitted across every segment, when there were enough samples to fit one
+    pub trend: Option<DriftTrend>,
+    pub quality: DataQuality,
+    /// Pace or power decoupling over the same segments, when the front end asked for it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoupling: Option<DecouplingResult>,
+    /// Pace decoupling with the pace adjusted for grade, when the front end asked for it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grade_adjusted_decoupling: Option<DecouplingResult>,
+    /// How far heart rate was shifted to line up with the output before decoupling
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cardiac_lag: Option<CardiacLag>,
+    /// How hot it was, when there was a temp stream or an ambient temperature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<TemperatureContext>,
+    pub interpretation: Interpretation,
+    /// Where DFA α1 from beat-to-beat data puts the aerobic threshold, when RR intervals were
+    /// given, to check the drift result against
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dfa_alpha1: Option<DfaAlpha1>,
+    /// Mean RR, SDNN and RMSSD for the warm-up and each segment, when RR intervals were given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hrv: Option<SegmentHrv>,
+}
+
+impl DriftReport {
+    /// Adds decoupling, which the interpretation then prefers over heart rate drift
+    pub fn with_decoupling(self, decoupling: DecouplingResult) -> Self {
+        let first_heart_rate = self.segments[0].average_heart_rate.unwrap_or_default();
+        Self {
+            interpretation: Interpretation::new(self.drift, first_heart_rate, Some(&decoupling)),
+            decoupling: Some(decoupling),
+            ..self
+        }
+    }
+
+    /// Adds the temperature in each segment from `(time, °C)` samples, and flags or adjusts the
+    /// drift when it was hot. An adjusted drift replaces the drift in the verdict, and in the
+    /// interpretation unless that goes by decoupling.
+    pub fn with_temperature(
+        self,
+        temperatures: &[(Seconds, f64)],
+        averaging: Averaging,
+        options: &TemperatureOptions,
+    ) -> Self {
+        let temperature =
+            TemperatureContext::new(temperatures, &self.segments, self.drift, averaging, options);
+        let Some(adjusted_drift) = temperature.adjusted_drift else {
+            return Self {
+                temperature: Some(temperature),
+                ..self
+            };
+        };
+        let first_heart_rate = self.segments[0].average_heart_rate.unwrap_or_default();
+        let decoupling = self
+            .grade_adjusted_decoupling
+            .as_ref()
+            .or(self.decoupling.as_ref());
+        Self {
+            verdict: Verdict::from_drift(adjusted_drift),
+            interpretation: Interpretation::new(adjusted_drift, first_heart_rate, decoupling),
+            temperature: Some(temperature),
+            ..self
+        }
+    }
+
+    /// Adds decoupling from grade-adjusted pace, which the interpretation then prefers over raw
+    /// pace since the hills no longer count against the athlete
+    pub fn with_grade_adjusted_decoupling(self, decoupling: DecouplingResult) -> Self {
+        let first_heart_rate = self.segments[0].average_heart_rate.unwrap_or_default();
+        Self {
+            interpretation: Interpretation::new(self.drift, first_heart_rate, Some(&decoupling)),
+            grade_adjusted_decoupling: Some(decoupling),
+            ..self
+        }
+    }
+
+    /// Adds HRV for the warm-up and each segment from the cleaned beats, which are timed from
+    /// the start of the activity like the heart rate samples. Beats during stops are left out
+    /// when the segments were laid over moving time.
+    pub fn with_hrv(self, clean: &CleanBeats) -> Self {
+        let beats: Vec<Beat> = match &self.stops {
+            Some(stops) => clean
+                .beats
+                .iter()
+                .filter_map(|beat| {
+                    stops.moving_time(beat.time).map(|time| Beat {
+                        time,
+                        interval: beat.interval,
+                    })
+                })
+                .collect(),
+            None => clean.beats.clone(),
+        };
+        let segments: Vec<Segment> = self
+            .segments
+            .iter()
+            .map(|segment| Segment {
+                start: segment.start,
+                end: segment.end,
+            })
+            .collect();
+        let warm_up = Segment {
+            start: self.protocol.start,
+            end: segments
+                .first()
+                .map_or(self.protocol.start, |first| first.start),
+        };
+        Self {
+            hrv: Some(SegmentHrv::new(&beats, &warm_up, &segments)),
+            ..self
+        }
+    }
+}
+
+pub(crate) fn minutes_and_seconds(seconds: i32) -> String {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+impl fmt::Display for DriftReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let length = match &self.protocol.segment_length {
+            SegmentLength::Fixed(seconds) => format!("{} min", seconds / 60),
+            SegmentLength::Split => "equal".to_string(),
+            SegmentLength::Laps(_) => "lap".to_string(),
+        };
+        if let Some(detected_window) = &self.detected_window {
+            writeln!(f, "{detected_window}")?;
+        }
+        if let Some(stops) = &self.stops {
+            writeln!(f, "{stops}")?;
+        }
+        write!(f, "Protocol: ")?;
+        if self.protocol.start > 0 {
+            write!(
+                f,
+                "starts at {}, ",
+                minutes_and_seconds(self.protocol.start)
+            )?;
+        }
+        writeln!(
+            f,
+            "{} min warm-up, {} x {} segments",
+            self.protocol.warm_up / 60,
+            self.protocol.segments,
+            length
+        )?;
+        for (index, segment) in self.segments.iter().enumerate() {
+            write!(
+                f,
+                "Segment {} ({} - {}): {} samples",
+                index + 1,
+                minutes_and_seconds(segment.start),
+                minutes_and_seconds(segment.end),
+                segment.samples
+            )?;
+            if let (Some(average), Some(min), Some(max)) = (
+                segment.average_heart_rate,
+                segment.min_heart_rate,
+                segment.max_heart_rate,
+            ) {
+                write!(f, ", average {average:.1}, min {min}, max {max}")?;
+            }
+            if let Some(quality) = self.quality.segments.get(index) {
+                write!(f, ", {quality}")?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "Heart rate drift is {:.2}%", self.drift)?;
+        if let Some(confidence) = self.confidence {
+            write!(f, " {confidence}")?;
+        }
+        writeln!(f)?;
+        if let Some(trend) = self.trend {
+            writeln!(f, "Trend: {trend}")?;
+        }
+        if let Some(cardiac_lag) = self.cardiac_lag {
+            writeln!(f, "{cardiac_lag}")?;
+        }
+        if let Some(temperature) = &self.temperature {
+            writeln!(f, "{temperature}")?;
+        }
+        write!(f, "{}", self.interpretation)?;
+        if let Some(dfa_alpha1) = &self.dfa_alpha1 {
+            write!(f, "\n{dfa_alpha1}")?;
+            if let (Some(dfa), Some(drift)) = (
+                dfa_alpha1.threshold_heart_rate,
+                self.interpretation.aerobic_threshold,
+            ) {
+                write!(
+                    f,
+                    ", {:+.0} bpm from the threshold drift gives",
+                    dfa - drift
+                )?;
+            }
+        }
+        if let Some(hrv) = &self.hrv {
+            write!(f, "\n{hrv}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A report with nothing but the drift, starting at 140 bpm
+    fn report(drift: f64) -> DriftReport {
+        DriftReport {
+            protocol: DriftProtocol::uphill_athlete(),
+            detected_window: None,
+            stops: None,
+            segments: vec![],
+            drift,
+            verdict: Verdict::from_drift(drift),
+            confidence: None,
+            trend: None,
+            quality: DataQuality::default(),
+            decoupling: None,
+            grade_adjusted_decoupling: None,
+            cardiac_lag: None,
+            temperature: None,
+            dfa_alpha1: None,
+            hrv: None,
+            interpretation: Interpretation::new(drift, 140.0, None),
+        }
+    }
+
+    #[test]
+    fn test_verdict_follows_the_uphill_athlete_thresholds() {
+        assert_eq!(Verdict::TooFast, Verdict::from_drift(5.1));
+        assert_eq!(Verdict::InRange, Verdict::from_drift(5.0));
+        assert_eq!(Verdict::InRange, Verdict::from_drift(3.5));
+        assert_eq!(Verdict::TooSlow, Verdict::from_drift(3.4));
+    }
+
+    #[test]
+    fn test_report_serializes_for_javascript() {
+        let report = report(4.2);
+
+        let json = serde_json::to_value(&report).unwrap();
+
+        assert_eq!("inRange", json["verdict"]);
+        assert_eq!(900, json["protocol"]["warmUp"]);
+        assert!(json.get("decoupling").is_none());
+        assert_eq!(140.0, json["interpretation"]["aerobicThreshold"]);
+        assert!(json.get("dfaAlpha1").is_none());
+        assert!(json.get("hrv").is_none());
+    }
+
+    #[test]
+    fn test_heat_adjusted_drift_decides_the_verdict() {
+        let report = DriftReport {
+            segments: vec![SegmentSummary {
+                start: 900,
+                end: 2700,
+                samples: 1,
+                average_heart_rate: Some(140.0),
+                min_heart_rate: Some(140.0),
+                max_heart_rate: Some(140.0),
+            }],
+            ..report(6.0)
+        };
+        let options = TemperatureOptions {
+            ambient_temperature: Some(30.0),
+            heat_adjustment: Some(0.3),
+            ..TemperatureOptions::default()
+        };
+
+        let report = report.with_temperature(&[], Averaging::Unweighted, &options);
+
+        assert_eq!(6.0, report.drift);
+        assert_eq!(Verdict::InRange, report.verdict);
+        assert_eq!(4.5, report.interpretation.percentage);
+        assert_eq!(Some(140.0), report.interpretation.aerobic_threshold);
+    }
+
+    #[test]
+    fn test_dfa_threshold_is_shown_next_to_the_one_from_drift() {
+        let report = DriftReport {
+            dfa_alpha1: Some(DfaAlpha1 {
+                series: Default::default(),
+                artifacts: 1.0,
+                threshold_heart_rate: Some(143.0),
+                extrapolated: false,
+            }),
+            ..report(4.2)
+        };
+
+        assert!(report
+            .to_string()
+            .ends_with("crosses 0.75 at 143 bpm, +3 bpm from the threshold drift gives"));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::averaging::{average, Averaging};
+use crate::heart_rate_drift::{all_whole_seconds, HeartRateAtTime, Seconds};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_MINUTE: i32 = 60;
+
+/// How long the rolling drift windows are and how far they move between points
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RollingOptions {
+    /// Length of each of the two compared windows for the rolling drift, in minutes
+    #[arg(long, default_value_t = RollingOptions::default().rolling_window)]
+    pub rolling_window: i32,
+    /// Seconds the rolling windows move between points
+    #[arg(long, default_value_t = RollingOptions::default().rolling_step)]
+    pub rolling_step: i32,
+}
+
+impl Default for RollingOptions {
+    fn default() -> Self {
+        Self {
+            rolling_window: 10,
+            rolling_step: 60,
+        }
+    }
+}
+
+/// How the command line prints the rolling drift
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum RollingFormat {
+    Csv,
+    Json,
+}
+
+/// Drift over time. `drifts[i]` compares the window before `times[i]` with the window after it.
+/// Kept as two columns so they can go straight to JavaScript as typed arrays.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RollingDrift {
+    #[serde(serialize_with = "all_whole_seconds")]
+    pub times: Vec<Seconds>,
+    pub drifts: Vec<f64>,
+}
+
+impl RollingDrift {
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("time,drift\n");
+        for (time, drift) in self.times.iter().zip(&self.drifts) {
+            csv.push_str(&format!("{time},{drift}\n"));
+        }
+        csv
+    }
+}
+
+/// Slides a pair of adjacent windows across the activity, which must be in time order. Points
+/// where either window has no heart rate are left out.
+pub fn rolling_drift(
+    samples: &[HeartRateAtTime],
+    options: &RollingOptions,
+    averaging: Averaging,
+) -> RollingDrift {
+    let mut rolling = RollingDrift::default();
+    let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+        return rolling;
+    };
+    let window = f64::from(options.rolling_window * SECONDS_PER_MINUTE);
+    if window <= 0.0 || options.rolling_step <= 0 {
+        return rolling;
+    }
+
+    let mut boundary = first.time + window;
+    while boundary + window <= last.time + 1.0 {
+        let before = in_window(samples, boundary - window, boundary);
+        let after = in_window(samples, boundary, boundary + window);
+        if let (Some(before), Some(after)) =
+            (average(&before, averaging), average(&after, averaging))
+        {
+            if before > 0.0 {
+                rolling.times.push(boundary);
+                rolling.drifts.push((after - before) / before * 100.0);
+            }
+        }
+        boundary += f64::from(options.rolling_step);
+    }
+    rolling
+}
+
+fn in_window(samples: &[HeartRateAtTime], start: Seconds, end: Seconds) -> Vec<(Seconds, f64)> {
+    let from = samples.partition_point(|sample| sample.time < start);
+    let to = samples.partition_point(|sample| sample.time < end);
+    samples[from..to]
+        .iter()
+        .map(|sample| (sample.time, f64::from(sample.heart_rate)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heart_rate_drift::every_second;
+
+    #[test]
+    fn test_steady_heart_rate_has_no_drift_anywhere() {
+        let samples = every_second(&[140; 3600]);
+
+        let rolling = rolling_drift(&samples, &RollingOptions::default(), Averaging::Unweighted);
+
+        assert_eq!(41, rolling.times.len());
+        assert_eq!(600.0, rolling.times[0]);
+        assert_eq!(3000.0, rolling.times[40]);
+        assert!(rolling.drifts.iter().all(|&drift| drift == 0.0));
+    }
+
+    #[test]
+    fn test_shows_when_heart_rate_starts_rising() {
+        let samples = every_second(&[[100; 1800], [110; 1800]].concat());
+        let options = RollingOptions {
+            rolling_window: 5,
+            rolling_step: 300,
+        };
+
+        let rolling = rolling_drift(&samples, &options, Averaging::Unweighted);
+
+        assert_eq!(
+            vec![
+                300.0, 600.0, 900.0, 1200.0, 1500.0, 1800.0, 2100.0, 2400.0, 2700.0, 3000.0, 3300.0
+            ],
+            rolling.times
+        );
+        assert_eq!(10.0, rolling.drifts[5]);
+        assert_eq!(0.0, rolling.drifts[4]);
+        assert_eq!(0.0, rolling.drifts[6]);
+    }
+
+    #[test]
+    fn test_activity_shorter_than_two_windows_has_no_points() {
+        let samples = every_second(&[140; 1000]);
+
+        assert_eq!(
+            RollingDrift::default(),
+            rolling_drift(&samples, &RollingOptions::default(), Averaging::Trapezoidal)
+        );
+    }
+
+    #[test]
+    fn test_csv_has_a_header_and_a_row_per_point() {
+        let rolling = RollingDrift {
+            times: vec![600.0, 660.0],
+            drifts: vec![0.5, 1.25],
+        };
+
+        assert_eq!("time,drift\n600,0.5\n660,1.25\n", rolling.to_csv());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::heart_rate_drift::{HeartRateDriftError, Seconds};
+use serde::Deserialize;
+
+/// Intervals shorter than this, in milliseconds, are over 200 bpm and can't be real beats
+const MIN_INTERVAL: f64 = 300.0;
+/// Intervals longer than this, in milliseconds, are under 30 bpm and are missed beats
+const MAX_INTERVAL: f64 = 2000.0;
+/// Beats either side of an interval that it's compared against when looking for ectopic beats
+const NEIGHBOURS: usize = 5;
+/// RR values below this are taken to be in seconds rather than milliseconds
+const SECONDS_LIMIT: f64 = 10.0;
+
+/// How far a beat can stray from its neighbours before it's removed
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RrOptions {
+    /// Percentage an RR interval can differ from the median of the beats around it before it's
+    /// removed as an ectopic beat or an artifact
+    #[arg(long, default_value_t = RrOptions::default().ectopic_threshold)]
+    pub ectopic_threshold: f64,
+}
+
+impl Default for RrOptions {
+    fn default() -> Self {
+        Self {
+            ectopic_threshold: 20.0,
+        }
+    }
+}
+
+/// One heartbeat, at the end of the interval since the one before it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Beat {
+    pub time: Seconds,
+    /// Milliseconds since the previous beat
+    pub interval: f64,
+}
+
+/// The beats that are left once ectopic beats and artifacts are taken out, both in the time
+/// order of the beats they were cleaned from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CleanBeats {
+    pub beats: Vec<Beat>,
+    /// When each removed beat was, so the share of artifacts can be checked over any stretch
+    pub removed: Vec<Seconds>,
+}
+
+impl CleanBeats {
+    /// Percentage of all the beats that were removed
+    pub fn artifacts(&self) -> f64 {
+        artifact_share(self.removed.len(), self.beats.len())
+    }
+
+    /// Percentage of the beats from `start` up to `end` that were removed
+    pub fn artifacts_between(&self, start: Seconds, end: Seconds) -> f64 {
+        let removed = self.removed.partition_point(|&time| time < end)
+            - self.removed.partition_point(|&time| time < start);
+        let kept = self.beats.partition_point(|beat| beat.time < end)
+            - self.beats.partition_point(|beat| beat.time < start);
+        artifact_share(removed, kept)
+    }
+}
+
+fn artifact_share(removed: usize, kept: usize) -> f64 {
+    let total = removed + kept;
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * removed as f64 / total as f64
+    }
+}
+
+/// Times the beats from the start of the activity, for RR intervals in milliseconds recorded
+/// from the moment it started
+pub fn beats_from_intervals(intervals: &[f64]) -> Vec<Beat> {
+    let mut time = 0.0;
+    intervals
+        .iter()
+        .map(|&interval| {
+            time += interval / 1000.0;
+            Beat { time, interval }
+        })
+        .collect()
+}
+
+/// Reads an RR file: one interval per line, or `time,interval` with the time of the beat in
+/// seconds since the start of the activity. Intervals are in milliseconds, or seconds when
+/// they're below 10. Commas, semicolons, tabs and spaces all separate columns, and lines that
+/// don't start with a number, like a header, are skipped.
+pub fn parse_rr_intervals(text: &str) -> Result<Vec<Beat>, HeartRateDriftError> {
+    let mut beats = Vec::new();
+    let mut elapsed = 0.0;
+    for (index, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line
+            .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+            .filter(|field| !field.is_empty())
+            .collect();
+        let Some(Ok(first)) = fields.first().map(|field| field.parse::<f64>()) else {
+            continue;
+        };
+        let invalid = || HeartRateDriftError::InvalidRrInterval { line: index + 1 };
+        let (time, interval) = match fields.get(1) {
+            Some(interval) => (Some(first), interval.parse::<f64>().map_err(|_| invalid())?),
+            None => (None, first),
+        };
+        let interval = if interval < SECONDS_LIMIT {
+            interval * 1000.0
+        } else {
+            interval
+        };
+        if interval.is_nan() || interval <= 0.0 {
+            return Err(invalid());
+        }
+        elapsed += interval / 1000.0;
+        beats.push(Beat {
+            time: time.unwrap_or(elapsed),
+            interval,
+        });
+    }
+    Ok(beats)
+}
+
+/// Removes beats that can't be real, and ectopic beats, which differ from the median of the
+/// beats around them by more than the threshold. Beats are removed rather than replaced, which
+/// DFA α1 tolerates as long as only a few percent are removed.
+pub fn clean_beats(beats: &[Beat], options: &RrOptions) -> CleanBeats {
+    let mut clean = CleanBeats::default();
+    for (index, beat) in beats.iter().enumerate() {
+        let from = index.saturating_sub(NEIGHBOURS);
+        let to = (index + NEIGHBOURS + 1).min(beats.len());
+        let mut around: Vec<f64> = beats[from..to]
+            .iter()
+            .map(|beat| beat.interval)
+            .filter(|interval| (MIN_INTERVAL..=MAX_INTERVAL).contains(interval))
+            .collect();
+        around.sort_by(f64::total_cmp);
+        let ectopic = match around.len() {
+            0 => true,
+            count => {
+                let median = around[count / 2];
+                100.0 * (beat.interval - median).abs() / median > options.ectopic_threshold
+            }
+        };
+        if ectopic || !(MIN_INTERVAL..=MAX_INTERVAL).contains(&beat.interval) {
+            clean.removed.push(beat.time);
+        } else {
+            clean.beats.push(*beat);
+        }
+    }
+    clean
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intervals_are_timed_from_the_start() {
+        let beats = beats_from_intervals(&[800.0, 810.0]);
+
+        assert_eq!(0.8, beats[0].time);
+        assert_eq!(1.61, beats[1].time);
+    }
+
+    #[test]
+    fn test_rr_file_can_have_a_header_times_and_seconds() {
+        let beats = parse_rr_intervals("time,rr\n10.5,0.8\n11.31,810\n\n").unwrap();
+
+        assert_eq!(
+            vec![
+                Beat {
+                    time: 10.5,
+                    interval: 800.0
+                },
+                Beat {
+                    time: 11.31,
+                    interval: 810.0
+                }
+            ],
+            beats
+        );
+        assert_eq!(2, parse_rr_intervals("800\n810").unwrap().len());
+    }
+
+    #[test]
+    fn test_rr_file_with_a_bad_interval_is_an_error() {
+        assert_eq!(
+            Err(HeartRateDriftError::InvalidRrInterval { line: 3 }),
+            parse_rr_intervals("800\n810\n12.0,-5\n")
+        );
+    }
+
+    #[test]
+    fn test_ectopic_beat_and_the_pause_after_it_are_removed() {
+        let mut intervals = vec![800.0; 10];
+        intervals.extend([500.0, 1100.0]);
+        intervals.extend(vec![800.0; 10]);
+        let beats = beats_from_intervals(&intervals);
+
+        let clean = clean_beats(&beats, &RrOptions::default());
+
+        assert_eq!(20, clean.beats.len());
+        assert_eq!(vec![beats[10].time, beats[11].time], clean.removed);
+        assert_eq!(100.0 * 2.0 / 22.0, clean.artifacts());
+        assert_eq!(0.0, clean.artifacts_between(0.0, 8.0));
+        assert_eq!(100.0 * 2.0 / 3.0, clean.artifacts_between(7.9, 10.3));
+    }
+}
@@ -1,10 +1,17 @@
 mod http;
 
-use self::http::{AuthToken, Client, NullClient, SimpleHttpClient, Url};
+pub use self::http::{AuthToken, HttpError};
+use self::http::{Client, NullClient, SimpleHttpClient, Url};
+use crate::heart_rate_drift::{
+    try_combine_hr_with_time, BeatsPerMinute, HeartRateAtTime, HeartRateDriftError, Seconds,
+};
+use crate::validation::Repair;
 use serde::Deserialize;
 use thiserror::Error;
 
 const STRAVA_API: &str = "https://www.strava.com/api/v3/activities";
+const STREAM_KEYS: &str =
+    "heartrate,time,velocity_smooth,distance,watts,cadence,moving,altitude,temp";
 
 // Format on save
 // Clippy not cargo check
@@ -12,26 +19,69 @@ const STRAVA_API: &str = "https://www.strava.com/api/v3/activities";
 
 pub struct ActivityID(pub String);
 
+/// One of Strava's streams, keyed by type
 #[derive(Deserialize)]
-pub struct Samples {
-    data: Vec<i16>,
+pub struct Samples<T> {
+    data: Vec<T>,
 }
 
 #[derive(Deserialize)]
 pub struct StravaData {
-    heartrate: Samples,
-    time: Samples,
+    heartrate: Samples<BeatsPerMinute>,
+    time: Samples<Seconds>,
+    velocity_smooth: Option<Samples<f64>>,
+    distance: Option<Samples<f64>>,
+    watts: Option<Samples<f64>>,
+    cadence: Option<Samples<f64>>,
+    moving: Option<Samples<bool>>,
+    altitude: Option<Samples<f64>>,
+    temp: Option<Samples<f64>>,
 }
 
 pub struct HeartRateSamples {
-    pub rates: Vec<i16>,
-    pub times: Vec<i16>,
+    pub rates: Vec<BeatsPerMinute>,
+    pub times: Vec<Seconds>,
+    /// Metres per second. Missing for activities without GPS
+    pub velocities: Option<Vec<f64>>,
+    /// Cumulative metres. Missing for activities without GPS
+    pub distances: Option<Vec<f64>>,
+    /// Missing unless the activity was recorded with a power meter
+    pub watts: Option<Vec<f64>>,
+    /// Used to spot a wrist sensor locked onto arm swing
+    pub cadence: Option<Vec<f64>>,
+    /// Whether Strava thinks the athlete was moving, used to leave stops out of the test
+    pub moving: Option<Vec<bool>>,
+    /// Metres above sea level, for grade-adjusted pace. Missing for activities without GPS
+    pub altitudes: Option<Vec<f64>>,
+    /// °C as read by the device. Missing unless it has a thermometer
+    pub temperatures: Option<Vec<f64>>,
+}
+
+impl HeartRateSamples {
+    /// The samples the drift is calculated from, checked and repaired as `repairs` allows
+    pub fn heart_rates_at_time(
+        &self,
+        repairs: &[Repair],
+    ) -> Result<Vec<HeartRateAtTime>, HeartRateDriftError> {
+        try_combine_hr_with_time(&self.rates, &self.times, repairs)
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum ErrorGettingHeartRateData {
     #[error("Error connecting to Strava")]
     ConnectionError,
+    #[error("Strava didn't answer with the data expected")]
+    UnexpectedResponse,
+}
+
+impl From<HttpError> for ErrorGettingHeartRateData {
+    fn from(error: HttpError) -> Self {
+        match error {
+            HttpError::Json(_) => Self::UnexpectedResponse,
+            HttpError::NotFound(_) => Self::ConnectionError,
+        }
+    }
 }
 
 pub struct Strava<T: SimpleHttpClient> {
@@ -49,27 +99,33 @@ impl<T: SimpleHttpClient> Strava<T> {
         let res = self
             .strava_client
             .request(&Url(full_url), token)
-            .await
-            .expect("BE GOOD") // TODO: Fix!
+            .await?
             .json::<StravaData>()
-            .await
-            .expect("Be JSON"); // TODO: Fix!
+            .await?;
 
         Ok(HeartRateSamples {
             rates: res.heartrate.data,
             times: res.time.data,
+            velocities: res.velocity_smooth.map(|samples| samples.data),
+            distances: res.distance.map(|samples| samples.data),
+            watts: res.watts.map(|samples| samples.data),
+            cadence: res.cadence.map(|samples| samples.data),
+            moving: res.moving.map(|samples| samples.data),
+            altitudes: res.altitude.map(|samples| samples.data),
+            temperatures: res.temp.map(|samples| samples.data),
         })
     }
 }
 
+/// Strava that answers with canned responses, for testing code that calls it
 impl Strava<NullClient> {
-    fn null() -> Self {
+    pub fn null() -> Self {
         Self {
             strava_client: Client::create_null(),
         }
     }
 
-    fn with_activity(
+    pub fn with_activity(
         self,
         token: AuthToken,
         activity: &ActivityID,
@@ -87,7 +143,10 @@ impl Strava<NullClient> {
 }
 
 fn construct_activity_url(activity: &ActivityID) -> String {
-    format!("https://{STRAVA_API}/{}", activity.0)
+    format!(
+        "{STRAVA_API}/{}/streams?keys={STREAM_KEYS}&key_by_type=true",
+        activity.0
+    )
 }
 
 #[cfg(test)]
@@ -138,6 +197,151 @@ mod tests {
             .unwrap();
 
         assert_eq!(result.rates, vec![2]);
-        assert_eq!(result.times, vec![3]);
+        assert_eq!(result.times, vec![3.0]);
+        assert!(result.velocities.is_none());
+        assert!(result.distances.is_none());
+        assert!(result.watts.is_none());
+        assert!(result.moving.is_none());
+    }
+
+    #[tokio::test]
+    async fn get_activity_heart_rate_converts_velocity_distance_and_watts() {
+        let json = "{
+            \"heartrate\": {
+                \"data\": [2]
+            },
+            \"time\": {
+                \"data\": [3]
+            },
+            \"velocity_smooth\": {
+                \"data\": [2.5]
+            },
+            \"distance\": {
+                \"data\": [10.2]
+            },
+            \"watts\": {
+                \"data\": [180]
+            },
+            \"moving\": {
+                \"data\": [true]
+            },
+            \"altitude\": {
+                \"data\": [312.4]
+            },
+            \"temp\": {
+                \"data\": [27]
+            }
+        }";
+
+        let token = AuthToken("token".to_string());
+        let activity = ActivityID("activity-id".to_string());
+        let strava = Strava::null().with_activity(token.clone(), &activity, json);
+
+        let result = strava
+            .get_activity_heart_rate(&token, &activity)
+            .await
+            .unwrap();
+
+        assert_eq!(result.velocities, Some(vec![2.5]));
+        assert_eq!(result.distances, Some(vec![10.2]));
+        assert_eq!(result.watts, Some(vec![180.0]));
+        assert_eq!(result.moving, Some(vec![true]));
+        assert_eq!(result.altitudes, Some(vec![312.4]));
+        assert_eq!(result.temperatures, Some(vec![27.0]));
+    }
+
+    #[tokio::test]
+    async fn get_activity_heart_rate_handles_activities_longer_than_nine_hours() {
+        let json = "{
+            \"heartrate\": {
+                \"data\": [120, 135, 128]
+            },
+            \"time\": {
+                \"data\": [0, 32768, 200000]
+            }
+        }";
+
+        let token = AuthToken("token".to_string());
+        let activity = ActivityID("activity-id".to_string());
+        let strava = Strava::null().with_activity(token.clone(), &activity, json);
+
+        let result = strava
+            .get_activity_heart_rate(&token, &activity)
+            .await
+            .unwrap();
+
+        assert_eq!(result.times, vec![0.0, 32768.0, 200000.0]);
+        assert_eq!(
+            result.heart_rates_at_time(&[]).unwrap().last(),
+            Some(&HeartRateAtTime {
+                heart_rate: 128,
+                time: 200000.0
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn heart_rates_at_time_refuses_streams_that_do_not_line_up() {
+        let json = "{
+            \"heartrate\": {
+                \"data\": [120, 135]
+            },
+            \"time\": {
+                \"data\": [0, 1, 2]
+            }
+        }";
+
+        let token = AuthToken("token".to_string());
+        let activity = ActivityID("activity-id".to_string());
+        let strava = Strava::null().with_activity(token.clone(), &activity, json);
+
+        let result = strava
+            .get_activity_heart_rate(&token, &activity)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            result.heart_rates_at_time(&[]),
+            Err(HeartRateDriftError::LengthMismatch {
+                stream: "heartrate",
+                expected: 3,
+                found: 2
+            })
+        );
+        assert_eq!(
+            result
+                .heart_rates_at_time(&[Repair::Truncate])
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn get_activity_heart_rate_is_an_error_when_strava_does_not_answer() {
+        let token = AuthToken("token".to_string());
+        let activity = ActivityID("activity-id".to_string());
+        let strava = Strava::null();
+
+        let result = strava.get_activity_heart_rate(&token, &activity).await;
+
+        assert!(matches!(
+            result,
+            Err(ErrorGettingHeartRateData::ConnectionError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn get_activity_heart_rate_is_an_error_when_the_answer_is_not_streams() {
+        let token = AuthToken("token".to_string());
+        let activity = ActivityID("activity-id".to_string());
+        let strava = Strava::null().with_activity(token.clone(), &activity, "{\"message\": \"x\"}");
+
+        let result = strava.get_activity_heart_rate(&token, &activity).await;
+
+        assert!(matches!(
+            result,
+            Err(ErrorGettingHeartRateData::UnexpectedResponse)
+        ));
     }
 }
@@ -0,0 +1,74 @@
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::future::Future;
+use thiserror::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Url(pub String);
+
+/// An OAuth access token, sent as a bearer token
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AuthToken(pub String);
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("Nothing answers at {0}")]
+    NotFound(String),
+    #[error("The response isn't the JSON expected: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// The one thing the Strava client needs from HTTP: an authenticated GET returning the body
+pub trait SimpleHttpClient {
+    fn get(&self, url: &Url, token: &AuthToken) -> impl Future<Output = Result<String, HttpError>>;
+}
+
+/// Answers with canned responses instead of going to the network, so the code that calls
+/// Strava can be tested without it
+#[derive(Default)]
+pub struct NullClient {
+    responses: HashMap<(AuthToken, Url), String>,
+}
+
+impl SimpleHttpClient for NullClient {
+    async fn get(&self, url: &Url, token: &AuthToken) -> Result<String, HttpError> {
+        self.responses
+            .get(&(token.clone(), url.clone()))
+            .cloned()
+            .ok_or_else(|| HttpError::NotFound(url.0.clone()))
+    }
+}
+
+/// The body of a response that came back
+pub struct Response(String);
+
+impl Response {
+    pub async fn json<T: DeserializeOwned>(self) -> Result<T, HttpError> {
+        Ok(serde_json::from_str(&self.0)?)
+    }
+}
+
+pub struct Client<T: SimpleHttpClient> {
+    http: T,
+}
+
+impl<T: SimpleHttpClient> Client<T> {
+    pub async fn request(&self, url: &Url, token: &AuthToken) -> Result<Response, HttpError> {
+        self.http.get(url, token).await.map(Response)
+    }
+}
+
+impl Client<NullClient> {
+    pub fn create_null() -> Self {
+        Self {
+            http: NullClient::default(),
+        }
+    }
+
+    /// Answers `response` to a request for `url` made with `token`. Anything else isn't found.
+    pub fn map_authenticated_url(self, token: AuthToken, url: Url, response: String) -> Self {
+        let mut http = self.http;
+        http.responses.insert((token, url), response);
+        Self { http }
+    }
+}
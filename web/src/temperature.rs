@@ -0,0 +1,193 @@
+use crate::averaging::{average, Averaging};
+use crate::heart_rate_drift::Seconds;
+use crate::report::SegmentSummary;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The air temperature, when a result counts as taken in the heat, and how much drift to take
+/// off for it
+#[derive(clap::Args, Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TemperatureOptions {
+    /// Air temperature during the test in °C. Watches read their own temperature, which body
+    /// heat pushes above the air's, so this is preferred over the temp stream when given
+    #[arg(long)]
+    pub ambient_temperature: Option<f64>,
+    /// °C above which the result is flagged as taken in the heat
+    #[arg(long, default_value_t = TemperatureOptions::default().hot_temperature)]
+    pub hot_temperature: f64,
+    /// Percentage points of drift to take off for every °C above the hot temperature. How much
+    /// heat adds varies a lot between athletes, so compare your own cool and hot tests to pick it.
+    /// The verdict then goes by the adjusted drift
+    #[arg(long)]
+    pub heat_adjustment: Option<f64>,
+}
+
+impl Default for TemperatureOptions {
+    fn default() -> Self {
+        Self {
+            ambient_temperature: None,
+            hot_temperature: 25.0,
+            heat_adjustment: None,
+        }
+    }
+}
+
+/// How hot the test was, so a hot day's drift isn't compared naively with a cool day's
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TemperatureContext {
+    /// Average of the temp stream in each segment, in °C. Missing for segments without any
+    /// readings, or all of them when there was no temp stream.
+    pub segments: Vec<Option<f64>>,
+    /// The temperature the flag and adjustment are based on: the ambient temperature when
+    /// given, otherwise the average over the compared segments
+    pub temperature: Option<f64>,
+    pub hot: bool,
+    /// Drift with the heat adjustment taken off, when one was given and it was hot
+    pub adjusted_drift: Option<f64>,
+}
+
+impl TemperatureContext {
+    /// `temperatures` are `(time, °C)` samples in time order
+    pub fn new(
+        temperatures: &[(Seconds, f64)],
+        segments: &[SegmentSummary],
+        drift: f64,
+        averaging: Averaging,
+        options: &TemperatureOptions,
+    ) -> Self {
+        let segment_temperatures: Vec<Option<f64>> = segments
+            .iter()
+            .map(|segment| {
+                let inside: Vec<(Seconds, f64)> = temperatures
+                    .iter()
+                    .filter(|(time, _)| {
+                        *time >= f64::from(segment.start) && *time < f64::from(segment.end)
+                    })
+                    .copied()
+                    .collect();
+                average(&inside, averaging)
+            })
+            .collect();
+        let compared = [
+            segment_temperatures.first().copied().flatten(),
+            segment_temperatures.last().copied().flatten(),
+        ];
+        let measured = match compared {
+            [Some(first), Some(last)] => Some((first + last) / 2.0),
+            [first, last] => first.or(last),
+        };
+        let temperature = options.ambient_temperature.or(measured);
+        let above = temperature.map_or(0.0, |temperature| temperature - options.hot_temperature);
+        let hot = above > 0.0;
+
+        Self {
+            segments: segment_temperatures,
+            temperature,
+            hot,
+            adjusted_drift: options
+                .heat_adjustment
+                .filter(|_| hot)
+                .map(|per_degree| drift - per_degree * above),
+        }
+    }
+}
+
+impl fmt::Display for TemperatureContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let segments: Vec<String> = self
+            .segments
+            .iter()
+            .map(|temperature| match temperature {
+                Some(temperature) => format!("{temperature:.1}°C"),
+                None => "-".to_string(),
+            })
+            .collect();
+        write!(f, "Temperature by segment: {}", segments.join(", "))?;
+        if self.hot {
+            if let Some(temperature) = self.temperature {
+                write!(
+                    f,
+                    "\nIt was hot ({temperature:.1}°C), which pushes drift up"
+                )?;
+            }
+        }
+        if let Some(adjusted_drift) = self.adjusted_drift {
+            write!(f, "\nAdjusted for heat, drift is {adjusted_drift:.2}%")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments() -> Vec<SegmentSummary> {
+        [(900, 2700), (2700, 4500)]
+            .iter()
+            .map(|&(start, end)| SegmentSummary {
+                start,
+                end,
+                samples: 0,
+                average_heart_rate: None,
+                min_heart_rate: None,
+                max_heart_rate: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_temperature_is_averaged_per_segment() {
+        let temperatures = [
+            (1000.0, 20.0),
+            (2000.0, 22.0),
+            (3000.0, 24.0),
+            (4000.0, 26.0),
+        ];
+
+        let context = TemperatureContext::new(
+            &temperatures,
+            &segments(),
+            4.0,
+            Averaging::Unweighted,
+            &TemperatureOptions::default(),
+        );
+
+        assert_eq!(vec![Some(21.0), Some(25.0)], context.segments);
+        assert_eq!(Some(23.0), context.temperature);
+        assert!(!context.hot);
+    }
+
+    #[test]
+    fn test_hot_day_is_flagged_and_adjusted() {
+        let options = TemperatureOptions {
+            ambient_temperature: Some(30.0),
+            heat_adjustment: Some(0.25),
+            ..Default::default()
+        };
+
+        let context =
+            TemperatureContext::new(&[], &segments(), 6.0, Averaging::Unweighted, &options);
+
+        assert_eq!(vec![None, None], context.segments);
+        assert!(context.hot);
+        assert_eq!(Some(4.75), context.adjusted_drift);
+    }
+
+    #[test]
+    fn test_cool_day_is_not_adjusted() {
+        let options = TemperatureOptions {
+            ambient_temperature: Some(12.0),
+            heat_adjustment: Some(0.25),
+            ..Default::default()
+        };
+
+        let context =
+            TemperatureContext::new(&[], &segments(), 4.0, Averaging::Unweighted, &options);
+
+        assert!(!context.hot);
+        assert_eq!(None, context.adjusted_drift);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::heart_rate_drift::Seconds;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const SECONDS_PER_HOUR: f64 = 3600.0;
+/// Theil-Sen looks at every pair of samples, so longer windows are thinned to this many first
+const MAX_THEIL_SEN_SAMPLES: usize = 1000;
+
+/// How a straight line is fitted to heart rate over time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fit {
+    /// Ordinary least squares
+    #[default]
+    Linear,
+    /// The median of the slopes between every pair of samples, which a surge or a dropout
+    /// barely moves
+    TheilSen,
+}
+
+/// Drift as the slope of heart rate across the whole test rather than the difference between
+/// two averages, so a surge at the start of the last segment doesn't decide the result
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriftTrend {
+    pub fit: Fit,
+    pub bpm_per_hour: f64,
+    /// The slope as a percentage of the fitted heart rate at the start of the window
+    pub percent_per_hour: f64,
+    /// How much of the variation in heart rate the line explains. Theil-Sen doesn't minimise
+    /// squared error, so with it this can drop below zero.
+    pub r_squared: f64,
+    /// Standard deviation of heart rate around the line, in beats per minute
+    pub residual_spread: f64,
+    /// The percentage rise the line gives between the middles of the first and last segments,
+    /// to set against the drift from segment averages
+    pub drift: f64,
+    #[serde(skip)]
+    intercept: f64,
+}
+
+impl DriftTrend {
+    /// Heart rate on the fitted line at `time`
+    pub fn at(&self, time: f64) -> f64 {
+        self.intercept + self.bpm_per_hour / SECONDS_PER_HOUR * time
+    }
+}
+
+/// Fits a line to `(time, heart rate)` samples one at a time. Least squares only needs running
+/// totals, but Theil-Sen needs every sample, so those are kept when it's the fit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrendFit {
+    fit: Fit,
+    count: usize,
+    mean_time: f64,
+    mean_rate: f64,
+    /// Sums of squared deviations from the means, and of their products, kept with Welford's
+    /// method so long activities don't lose precision
+    time_squares: f64,
+    rate_squares: f64,
+    products: f64,
+    points: Vec<(Seconds, f64)>,
+}
+
+impl TrendFit {
+    pub fn new(fit: Fit) -> Self {
+        Self {
+            fit,
+            count: 0,
+            mean_time: 0.0,
+            mean_rate: 0.0,
+            time_squares: 0.0,
+            rate_squares: 0.0,
+            products: 0.0,
+            points: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, time: Seconds, rate: f64) {
+        self.count += 1;
+        let count = self.count as f64;
+        let (time_change, rate_change) = (time - self.mean_time, rate - self.mean_rate);
+        self.mean_time += time_change / count;
+        self.mean_rate += rate_change / count;
+        self.time_squares += time_change * (time - self.mean_time);
+        self.rate_squares += rate_change * (rate - self.mean_rate);
+        self.products += time_change * (rate - self.mean_rate);
+        if self.fit == Fit::TheilSen {
+            self.points.push((time, rate));
+        }
+    }
+
+    /// The line from `start` onwards. `first_middle` and `last_middle` are the midpoints of the
+    /// compared segments, used for the trend's equivalent drift. Needs at least two samples at
+    /// different times.
+    pub fn finish(&self, start: i32, first_middle: f64, last_middle: f64) -> Option<DriftTrend> {
+        let (slope, intercept, residual) = match self.fit {
+            Fit::Linear => {
+                if self.time_squares <= 0.0 {
+                    return None;
+                }
+                // Least squares leaves whatever variation the slope doesn't explain
+                let slope = self.products / self.time_squares;
+                (
+                    slope,
+                    self.mean_rate - slope * self.mean_time,
+                    (self.rate_squares - slope * self.products).max(0.0),
+                )
+            }
+            Fit::TheilSen => {
+                let (slope, intercept) = theil_sen(&self.points)?;
+                let residual = self
+                    .points
+                    .iter()
+                    .map(|(time, rate)| (rate - (intercept + slope * time)).powi(2))
+                    .sum();
+                (slope, intercept, residual)
+            }
+        };
+        let total = self.rate_squares;
+
+        let trend = DriftTrend {
+            fit: self.fit,
+            bpm_per_hour: slope * SECONDS_PER_HOUR,
+            percent_per_hour: 0.0,
+            r_squared: if total > 0.0 {
+                1.0 - residual / total
+            } else {
+                1.0
+            },
+            residual_spread: (residual / self.count as f64).sqrt(),
+            drift: 0.0,
+            intercept,
+        };
+        let first = trend.at(first_middle);
+        Some(DriftTrend {
+            percent_per_hour: 100.0 * trend.bpm_per_hour / trend.at(f64::from(start)),
+            drift: 100.0 * (trend.at(last_middle) - first) / first,
+            ..trend
+        })
+    }
+}
+
+/// Median pairwise slope, with the intercept as the median of what each sample implies
+fn theil_sen(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let step = points.len().div_ceil(MAX_THEIL_SEN_SAMPLES).max(1);
+    let thinned: Vec<(f64, f64)> = points.iter().copied().step_by(step).collect();
+
+    let mut slopes = Vec::new();
+    for (index, (time, rate)) in thinned.iter().enumerate() {
+        for (later_time, later_rate) in &thinned[index + 1..] {
+            if later_time != time {
+                slopes.push((later_rate - rate) / (later_time - time));
+            }
+        }
+    }
+    let slope = median(&mut slopes)?;
+    let mut intercepts: Vec<f64> = points
+        .iter()
+        .map(|(time, rate)| rate - slope * time)
+        .collect();
+    Some((slope, median(&mut intercepts)?))
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    })
+}
+
+impl fmt::Display for DriftTrend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:+.1} bpm/h ({:+.2}%/h), R² {:.2}, residual spread {:.1} bpm, {:.2}% between segment middles",
+            self.bpm_per_hour, self.percent_per_hour, self.r_squared, self.residual_spread, self.drift
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One sample a minute for an hour, rising `per_minute` beats a minute from 100
+    fn rising(per_minute: f64) -> Vec<(Seconds, f64)> {
+        (0..=60)
+            .map(|minute| {
+                (
+                    f64::from(minute * 60),
+                    100.0 + per_minute * f64::from(minute),
+                )
+            })
+            .collect()
+    }
+
+    fn fit_trend(samples: &[(Seconds, f64)], fit: Fit, last_middle: f64) -> Option<DriftTrend> {
+        let mut fitting = TrendFit::new(fit);
+        for &(time, rate) in samples {
+            fitting.push(time, rate);
+        }
+        fitting.finish(0, 0.0, last_middle)
+    }
+
+    #[test]
+    fn test_linear_fit_of_a_straight_line() {
+        let trend = fit_trend(&rising(0.25), Fit::Linear, 3600.0).unwrap();
+
+        assert_eq!(15.0, trend.bpm_per_hour);
+        assert_eq!(15.0, trend.percent_per_hour);
+        assert_eq!(1.0, trend.r_squared);
+        assert_eq!(15.0, trend.drift);
+    }
+
+    #[test]
+    fn test_theil_sen_ignores_a_surge() {
+        let mut samples = rising(0.25);
+        for sample in &mut samples[30..34] {
+            sample.1 += 30.0;
+        }
+
+        let robust = fit_trend(&samples, Fit::TheilSen, 3600.0).unwrap();
+        let linear = fit_trend(&samples, Fit::Linear, 3600.0).unwrap();
+
+        assert_eq!(15.0, robust.bpm_per_hour);
+        assert!(linear.residual_spread > 5.0);
+    }
+
+    #[test]
+    fn test_flat_heart_rate_has_no_slope() {
+        let trend = fit_trend(&rising(0.0), Fit::Linear, 3600.0).unwrap();
+
+        assert_eq!(0.0, trend.bpm_per_hour);
+        assert_eq!(0.0, trend.residual_spread);
+    }
+
+    #[test]
+    fn test_samples_at_one_time_cannot_be_fitted() {
+        assert_eq!(
+            None,
+            fit_trend(&[(0.0, 100.0), (0.0, 110.0)], Fit::Linear, 1.0)
+        );
+        assert_eq!(None, fit_trend(&[], Fit::TheilSen, 1.0));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::heart_rate_drift::{HeartRateDriftError, Seconds};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fmt;
+
+/// A problem with the streams that is fixed instead of refusing to calculate
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Repair {
+    /// Put samples recorded out of order back in time order
+    Sort,
+    /// Keep only the first of several samples at the same time
+    Dedupe,
+    /// Cut every stream to the length of the shortest
+    Truncate,
+}
+
+/// What was done to the streams to make them usable
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Repairs {
+    /// Samples cut off the end so the streams have the same length
+    pub truncated: usize,
+    pub sorted: bool,
+    /// Samples dropped for having the same time as an earlier one
+    pub duplicates: usize,
+}
+
+impl Repairs {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The samples that passed validation, as indices into the streams in the order they should be
+/// used, so every stream recorded alongside the times can be put in the same order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamOrder {
+    pub indices: Vec<usize>,
+    pub repairs: Repairs,
+}
+
+impl StreamOrder {
+    /// `values` in the validated order. `values` must be at least as long as the times were
+    /// after truncating.
+    pub fn apply<T: Copy>(&self, values: &[T]) -> Vec<T> {
+        self.indices.iter().map(|&index| values[index]).collect()
+    }
+
+    /// Where each sample, by the index it was recorded at, ends up. None for samples that were
+    /// dropped, and the list stops at the last sample kept.
+    pub fn positions(&self) -> Vec<Option<usize>> {
+        let recorded = self.indices.iter().max().map_or(0, |&last| last + 1);
+        let mut positions = vec![None; recorded];
+        for (position, &index) in self.indices.iter().enumerate() {
+            positions[index] = Some(position);
+        }
+        positions
+    }
+}
+
+/// Checks `times`, and that the other streams, given as `(name, length)`, line up with it.
+/// Anything wrong is an error unless `repairs` allows fixing it. Negative, NaN and infinite
+/// times are always an error, since there's no telling what they were meant to be.
+pub fn validate_streams(
+    times: &[Seconds],
+    streams: &[(&'static str, usize)],
+    repairs: &[Repair],
+) -> Result<StreamOrder, HeartRateDriftError> {
+    let mut length = times.len();
+    for &(stream, found) in streams {
+        if found != times.len() {
+            if !repairs.contains(&Repair::Truncate) {
+                return Err(HeartRateDriftError::LengthMismatch {
+                    stream,
+                    expected: times.len(),
+                    found,
+                });
+            }
+            length = length.min(found);
+        }
+    }
+    let mut done = Repairs {
+        truncated: times.len() - length,
+        ..Default::default()
+    };
+    let times = &times[..length];
+    if let Some((index, &time)) = times
+        .iter()
+        .enumerate()
+        .find(|(_, time)| !time.is_finite() || **time < 0.0)
+    {
+        return Err(if time.is_finite() {
+            HeartRateDriftError::NegativeTime { index, time }
+        } else {
+            HeartRateDriftError::InvalidTime { index, time }
+        });
+    }
+
+    let mut indices: Vec<usize> = (0..length).collect();
+    if let Some(index) = (1..length).find(|&index| times[index] < times[index - 1]) {
+        if !repairs.contains(&Repair::Sort) {
+            return Err(HeartRateDriftError::UnsortedTimes {
+                index,
+                time: times[index],
+            });
+        }
+        // Stable, so of several samples at one time the first recorded stays first
+        indices.sort_by(|&first, &second| times[first].total_cmp(&times[second]));
+        done.sorted = true;
+    }
+    if let Some(pair) = indices
+        .windows(2)
+        .find(|pair| times[pair[0]] == times[pair[1]])
+    {
+        if !repairs.contains(&Repair::Dedupe) {
+            return Err(HeartRateDriftError::DuplicateTime {
+                time: times[pair[1]],
+            });
+        }
+        let before = indices.len();
+        indices.dedup_by_key(|index| times[*index]);
+        done.duplicates = before - indices.len();
+    }
+
+    Ok(StreamOrder {
+        indices,
+        repairs: done,
+    })
+}
+
+impl fmt::Display for Repairs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut done = Vec::new();
+        if self.truncated > 0 {
+            done.push(format!(
+                "cut {} samples off the end to line the streams up",
+                self.truncated
+            ));
+        }
+        if self.sorted {
+            done.push("sorted the samples back into time order".to_string());
+        }
+        if self.duplicates > 0 {
+            done.push(format!("dropped {} duplicate times", self.duplicates));
+        }
+        write!(f, "Repaired the streams: {}", done.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_streams_keep_every_sample_in_order() {
+        let order = validate_streams(&[0.0, 1.0, 2.5], &[("heartrate", 3)], &[]).unwrap();
+
+        assert_eq!(vec![0, 1, 2], order.indices);
+        assert!(order.repairs.is_empty());
+    }
+
+    #[test]
+    fn test_length_mismatch_is_an_error_unless_truncating() {
+        let times = [0.0, 1.0, 2.0, 3.0];
+
+        assert_eq!(
+            Err(HeartRateDriftError::LengthMismatch {
+                stream: "heartrate",
+                expected: 4,
+                found: 3
+            }),
+            validate_streams(&times, &[("heartrate", 3)], &[])
+        );
+
+        let order = validate_streams(&times, &[("heartrate", 3)], &[Repair::Truncate]).unwrap();
+        assert_eq!(vec![0, 1, 2], order.indices);
+        assert_eq!(1, order.repairs.truncated);
+    }
+
+    #[test]
+    fn test_unsorted_times_are_an_error_unless_sorting() {
+        let times = [0.0, 2.0, 1.0, 3.0];
+
+        assert_eq!(
+            Err(HeartRateDriftError::UnsortedTimes {
+                index: 2,
+                time: 1.0
+            }),
+            validate_streams(&times, &[], &[])
+        );
+
+        let order = validate_streams(&times, &[], &[Repair::Sort]).unwrap();
+        assert_eq!(vec![140, 142, 141, 143], order.apply(&[140, 141, 142, 143]));
+        assert!(order.repairs.sorted);
+    }
+
+    #[test]
+    fn test_duplicates_are_an_error_unless_deduping() {
+        let times = [0.0, 1.0, 1.0, 2.0];
+
+        assert_eq!(
+            Err(HeartRateDriftError::DuplicateTime { time: 1.0 }),
+            validate_streams(&times, &[], &[])
+        );
+
+        let order = validate_streams(&times, &[], &[Repair::Dedupe]).unwrap();
+        assert_eq!(vec![0, 1, 3], order.indices);
+        assert_eq!(1, order.repairs.duplicates);
+        assert_eq!(vec![Some(0), Some(1), None, Some(2)], order.positions());
+    }
+
+    #[test]
+    fn test_negative_time_is_always_an_error() {
+        assert_eq!(
+            Err(HeartRateDriftError::NegativeTime {
+                index: 1,
+                time: -5.0
+            }),
+            validate_streams(
+                &[0.0, -5.0],
+                &[],
+                &[Repair::Sort, Repair::Dedupe, Repair::Truncate]
+            )
+        );
+    }
+
+    #[test]
+    fn test_nan_and_infinite_times_are_invalid() {
+        assert!(matches!(
+            validate_streams(&[0.0, f64::NAN], &[], &[Repair::Sort]),
+            Err(HeartRateDriftError::InvalidTime { index: 1, .. })
+        ));
+        assert_eq!(
+            Err(HeartRateDriftError::InvalidTime {
+                index: 2,
+                time: f64::INFINITY
+            }),
+            validate_streams(&[0.0, 1.0, f64::INFINITY], &[], &[])
+        );
+    }
+}
@@ -99,10 +99,16 @@ addEventListener("TrunkApplicationStarted", async (event) => {
 				try {
 					// Parse the file content as JSON
 					const jsonData = JSON.parse(reader.result);
-					window.wasmBindings.calculate_heart_rate_drift(
+					const streams = new window.wasmBindings.ActivityStreams(
 						jsonData.heartrate.data,
 						jsonData.time.data
 					);
+					const report = JSON.parse(window.wasmBindings.calculate_heart_rate_drift(
+						streams,
+						JSON.stringify({ bootstrap: 1000 })
+					));
+					const margin = report.confidence ? ` ± ${report.confidence.margin.toFixed(2)}%` : '';
+					console.log(`Heart rate drift is ${report.drift.toFixed(2)}%${margin} (${report.verdict})`, report);
 
 					// You can now use the jsonData in your application
 				} catch (error) {